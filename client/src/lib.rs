@@ -2,9 +2,10 @@ use ipnet::IpNet;
 #[cfg(feature = "schema")]
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
-use std::net::SocketAddr;
-use std::ops::{Add, AddAssign, Deref, DerefMut};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::{Add, AddAssign, Bound, Deref, DerefMut, Sub};
 use thiserror::Error;
 use url::Url;
 use wireguard_keys::{Privkey, Pubkey, Secret};
@@ -17,6 +18,9 @@ use wireguard_keys::{Privkey, Pubkey, Secret};
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct GatewayPeerConnectedEvent {
     pub network: Pubkey,
+    /// Listen port of the network, the key of the network in the config
+    #[serde(default)]
+    pub listen_port: u16,
     pub peer: Pubkey,
     pub endpoint: SocketAddr,
 }
@@ -28,6 +32,9 @@ pub struct GatewayPeerConnectedEvent {
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct GatewayPeerDisconnectedEvent {
     pub network: Pubkey,
+    /// Listen port of the network, the key of the network in the config
+    #[serde(default)]
+    pub listen_port: u16,
     pub peer: Pubkey,
 }
 
@@ -35,28 +42,182 @@ pub struct GatewayPeerDisconnectedEvent {
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct GatewayPeerEndpointEvent {
     pub network: Pubkey,
+    /// Listen port of the network, the key of the network in the config
+    #[serde(default)]
+    pub listen_port: u16,
     pub peer: Pubkey,
     pub endpoint: SocketAddr,
 }
 
+/// Peer completed a new handshake.
+///
+/// This event is emitted whenever the latest handshake of an already connected peer advances,
+/// so that silently dying tunnels can be detected by the handshakes stopping.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct GatewayPeerHandshakeEvent {
+    pub network: Pubkey,
+    /// Listen port of the network, the key of the network in the config
+    #[serde(default)]
+    pub listen_port: u16,
+    pub peer: Pubkey,
+    /// Time of the handshake, as UNIX timestamp
+    pub time: usize,
+}
+
+/// Peer has exceeded its data quota.
+///
+/// This event is emitted once per quota period, when the traffic of the peer first exceeds its
+/// quota.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct GatewayPeerQuotaExceededEvent {
+    pub network: Pubkey,
+    pub peer: Pubkey,
+    /// Bytes transferred by the peer in the current quota period
+    pub usage: usize,
+    /// Quota of the peer, in bytes
+    pub quota: usize,
+}
+
+/// Network has never had a handshake.
+///
+/// This event is emitted once when a network with peers has been up for longer than the
+/// configured threshold without any of its peers completing a handshake, which usually means
+/// that the peers cannot reach it or use the wrong keys.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct GatewayNetworkNeverConnectedEvent {
+    pub network: Pubkey,
+    /// Time the network was first seen, as UNIX timestamp
+    pub since: usize,
+}
+
+/// This event is emitted when the MTU of a network could not be set on its interface, for
+/// example because it is too small for an interface carrying IPv6. The network is then applied
+/// with the interface's current MTU.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct GatewayMtuRejectedEvent {
+    pub network: Pubkey,
+    /// Listen port of the network, the key of the network in the config
+    pub listen_port: u16,
+    /// Interface the MTU was rejected for
+    pub interface: String,
+    /// MTU that was rejected
+    pub mtu: usize,
+    /// MTU the interface kept, if it could be read
+    pub current: Option<usize>,
+}
+
 /// Gateway event types
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub enum GatewayEvent {
     PeerConnected(GatewayPeerConnectedEvent),
     PeerDisconnected(GatewayPeerDisconnectedEvent),
     Endpoint(GatewayPeerEndpointEvent),
+    PeerHandshake(GatewayPeerHandshakeEvent),
+    PeerQuotaExceeded(GatewayPeerQuotaExceededEvent),
+    NetworkNeverConnected(GatewayNetworkNeverConnectedEvent),
+    MtuRejected(GatewayMtuRejectedEvent),
 }
 
 /// Possible errors that can happen when making a request to the gateway.
 #[derive(Error, Debug)]
 pub enum GatewayError {
-    #[error("An unknown error has occured")]
+    #[error("An unknown error has occurred")]
     Unknown,
+    #[error("The config is invalid: {0:}")]
+    Validation(String),
     #[cfg(feature = "api")]
-    #[error("An error making the request has occured: {0:}")]
+    #[error("An error making the request has occurred: {0:}")]
     Reqwest(#[from] reqwest::Error),
 }
 
+impl GatewayError {
+    /// Map the error of a failed apply, as sent in [`GatewayResponse::Apply`],
+    /// back to a `GatewayError`. Only validation failures map onto a variant,
+    /// other errors are only described by their message.
+    pub fn from_apply(message: &str) -> Option<GatewayError> {
+        message
+            .strip_prefix("The config is invalid: ")
+            .map(|reason| GatewayError::Validation(reason.to_string()))
+    }
+}
+
+impl From<ConfigError> for GatewayError {
+    fn from(error: ConfigError) -> Self {
+        GatewayError::Validation(error.to_string())
+    }
+}
+
+impl From<OverlapError> for GatewayError {
+    fn from(error: OverlapError) -> Self {
+        GatewayError::Validation(error.to_string())
+    }
+}
+
+/// Problems found when validating a configuration before it is applied.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("Peer {peer} has no preshared key, but the network requires one")]
+    MissingPresharedKey { peer: Pubkey },
+    #[error("Peer {peer} has endpoint {endpoint}, but the network is not reachable over its address family")]
+    UnreachableEndpoint { peer: Pubkey, endpoint: SocketAddr },
+    #[error("No free address left in the network's subnet to assign to peer {peer}")]
+    AddressesExhausted { peer: Pubkey },
+    #[error("Network has {peers} peers, but at most {max} are allowed")]
+    TooManyPeers { peers: usize, max: usize },
+    #[error(
+        "Network has MTU {mtu}, but it must be between {} and {}",
+        MTU_MIN,
+        MTU_MAX
+    )]
+    InvalidMtu { mtu: usize },
+    #[error("Network is keyed by port 0, which is not a valid listen port")]
+    ZeroPort,
+    #[error("Network {port} has listen port {listen_port}, but networks must listen on the port they are keyed by")]
+    ListenPortMismatch { port: u16, listen_port: u16 },
+}
+
+/// Two networks of a config whose subnets overlap, see
+/// [`GatewayConfig::check_overlaps`].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error(
+    "Networks {first} and {second} have overlapping subnets {first_address} and {second_address}"
+)]
+pub struct OverlapError {
+    pub first: u16,
+    pub first_address: IpNet,
+    pub second: u16,
+    pub second_address: IpNet,
+}
+
+/// Check that a network is keyed by a valid port, and that its listen port
+/// is either unset or the same as that port.
+fn validate_listen_port(port: u16, network: Option<&NetworkState>) -> Result<(), ConfigError> {
+    if port == 0 {
+        return Err(ConfigError::ZeroPort);
+    }
+    if let Some(network) = network {
+        if network.listen_port != 0 && network.listen_port != port {
+            return Err(ConfigError::ListenPortMismatch {
+                port,
+                listen_port: network.listen_port,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Smallest MTU a network may have. WireGuard carries IPv6, which requires
+/// links to have an MTU of at least 1280.
+pub const MTU_MIN: usize = 1280;
+
+/// Largest MTU a network may have. WireGuard packets are sent over the
+/// host's uplink, which typically has an MTU of 1500.
+pub const MTU_MAX: usize = 1500;
+
+/// Addresses the gateway assigned to peers, by listen port of their network
+/// and peer public key.
+pub type AddressAssignments = BTreeMap<u16, BTreeMap<Pubkey, IpNet>>;
+
 /// Represents the entire configuration state of the gateway.
 #[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[derive(Serialize, Deserialize, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
@@ -81,6 +242,14 @@ impl GatewayConfig {
         self.0
     }
 
+    /// Returns a view of the configuration which is safe to share, see
+    /// [`NetworkState::to_public`].
+    pub fn to_public(&self) -> BTreeMap<u16, PublicNetworkState> {
+        self.iter()
+            .map(|(port, network)| (*port, network.to_public()))
+            .collect()
+    }
+
     pub fn apply_partial(&mut self, partial: &GatewayConfigPartial) {
         for (port, network) in partial.iter() {
             match network {
@@ -89,6 +258,58 @@ impl GatewayConfig {
             };
         }
     }
+
+    /// Check that every network is keyed by a valid port which matches its
+    /// listen port, returning the first problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (port, network) in self.iter() {
+            validate_listen_port(*port, Some(network))?;
+        }
+        Ok(())
+    }
+
+    /// Check that the subnets of different networks are disjoint, returning
+    /// the first pair of networks found to overlap. The gateway rejects
+    /// configs that fail this check, so managers can use it to catch them
+    /// before sending them.
+    pub fn check_overlaps(&self) -> Result<(), OverlapError> {
+        for (first, first_network) in self.iter() {
+            for (second, second_network) in self.range((Bound::Excluded(first), Bound::Unbounded)) {
+                for first_address in &first_network.address {
+                    for second_address in &second_network.address {
+                        if first_address.contains(second_address)
+                            || second_address.contains(first_address)
+                        {
+                            return Err(OverlapError {
+                                first: *first,
+                                first_address: *first_address,
+                                second: *second,
+                                second_address: *second_address,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compute the partial config which turns this config into `other` when
+    /// applied. Networks that are unchanged are left out.
+    pub fn diff(&self, other: &GatewayConfig) -> GatewayConfigPartial {
+        let mut partial = GatewayConfigPartial::default();
+        for port in self.keys() {
+            if !other.contains_key(port) {
+                partial.insert(*port, None);
+            }
+        }
+        for (port, network) in other.iter() {
+            if self.get(port) != Some(network) {
+                partial.insert(*port, Some(network.clone()));
+            }
+        }
+        partial
+    }
 }
 
 /// Represents a partial configuration of the gateway. All ports are listed,
@@ -101,6 +322,15 @@ impl GatewayConfigPartial {
     pub fn into_inner(self) -> BTreeMap<u16, Option<NetworkState>> {
         self.0
     }
+
+    /// Check that every network is keyed by a valid port which matches its
+    /// listen port, returning the first problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (port, network) in self.iter() {
+            validate_listen_port(*port, network.as_ref())?;
+        }
+        Ok(())
+    }
 }
 
 impl Deref for GatewayConfigPartial {
@@ -117,11 +347,6 @@ impl DerefMut for GatewayConfigPartial {
     }
 }
 
-/// Default MTU for WireGuard networks.
-fn default_mtu() -> usize {
-    1420
-}
-
 /// Requests coming in for the gateway
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub enum GatewayRequest {
@@ -129,6 +354,29 @@ pub enum GatewayRequest {
     Apply(GatewayConfig),
     /// Apply partial config to gateway
     ApplyPartial(GatewayConfigPartial),
+    /// Query which peers are currently connected
+    Connected,
+    /// Pause the watchdog, no stats are read and no events emitted until it
+    /// is resumed.
+    PauseWatchdog,
+    /// Resume a paused watchdog.
+    ResumeWatchdog,
+    /// Query version, uptime and last apply time of the gateway
+    Info,
+    /// Query the handshake rate of every network
+    HandshakeRates,
+    /// Query the kernel interface counters of every network
+    InterfaceStats,
+    /// Query the config the gateway is currently running. The reply
+    /// includes the private keys and preshared keys of all networks.
+    Config,
+    /// Query the live status of all networks and their peers
+    Status,
+    /// Query the audit log of changes requested by the manager
+    AuditLog,
+    /// Acknowledge all buffered traffic up to and including this cursor,
+    /// so that it is not sent again on reconnect
+    TrafficAck(u64),
     /// Shut gateway down.
     Shutdown,
 }
@@ -142,6 +390,208 @@ pub enum GatewayResponse {
     Event(GatewayEvent),
     /// Result for the last apply operation
     Apply(Result<(), String>),
+    /// Peers which are currently connected
+    Connected(GatewayConnected),
+    /// Whether the watchdog is paused, sent after pausing or resuming it
+    WatchdogPaused(bool),
+    /// Version, uptime and last apply time of the gateway
+    Info(GatewayInfo),
+    /// Handshake rate of every network, by listen port
+    HandshakeRates(BTreeMap<u16, HandshakeRate>),
+    /// Kernel interface counters of every network, by listen port
+    InterfaceStats(BTreeMap<u16, NetworkInterfaceStats>),
+    /// Config the gateway is currently running, as last applied, including
+    /// the private keys and preshared keys of all networks
+    Config(GatewayConfig),
+    /// Live status of all networks and their peers, sent in response to
+    /// [`GatewayRequest::Status`] and after every watchdog run
+    Status(GatewayStatus),
+    /// Audit log entries, oldest first
+    AuditLog(Vec<AuditEntry>),
+    /// Traffic data which is kept until it is acknowledged, sent instead of
+    /// `Traffic` when the gateway buffers traffic
+    BufferedTraffic(BufferedTraffic),
+    /// Addresses assigned to peers by the last apply, sent after its result
+    /// if any network has `assign_addresses` set
+    Assigned(AddressAssignments),
+}
+
+/// Operational metadata of the gateway.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct GatewayInfo {
+    /// Version of the gateway
+    pub version: String,
+    /// Time the gateway was started, as UNIX timestamp
+    pub start_time: usize,
+    /// Time of the last successful apply, as UNIX timestamp
+    pub last_apply: Option<usize>,
+    /// How WireGuard is provided, `kernel` or `userspace`
+    pub wireguard: String,
+}
+
+/// Snapshot of the peers currently connected to the gateway, keyed by the
+/// listen port of the network they are connected to.
+///
+/// This is taken from the watchdog's cache, so it is only as fresh as the
+/// last watchdog run.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct GatewayConnected(BTreeMap<u16, BTreeMap<Pubkey, ConnectedPeer>>);
+
+impl GatewayConnected {
+    pub fn into_inner(self) -> BTreeMap<u16, BTreeMap<Pubkey, ConnectedPeer>> {
+        self.0
+    }
+}
+
+impl Deref for GatewayConnected {
+    type Target = BTreeMap<u16, BTreeMap<Pubkey, ConnectedPeer>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for GatewayConnected {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Connection state of a single connected peer.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ConnectedPeer {
+    /// Endpoint the peer is connecting from
+    pub endpoint: Option<SocketAddr>,
+    /// Latest handshake, as UNIX timestamp
+    pub latest_handshake: usize,
+}
+
+/// Entry of the audit log, recording a change requested by the manager.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct AuditEntry {
+    /// When the change was requested, as UNIX timestamp
+    pub time: usize,
+    /// Manager which requested the change
+    pub manager: String,
+    /// Identity the gateway is connected to the manager with
+    pub identity: String,
+    /// Requested change
+    pub operation: AuditOperation,
+    /// Error message if the change failed
+    pub error: Option<String>,
+}
+
+/// Change recorded in the audit log. This only lists the ports of the
+/// affected networks, so that no keys end up in the log.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub enum AuditOperation {
+    /// Full config applied, with the ports of all networks
+    Apply { networks: Vec<u16> },
+    /// Partial config applied, with the ports of changed and removed networks
+    ApplyPartial {
+        changed: Vec<u16>,
+        removed: Vec<u16>,
+    },
+    /// Gateway shut down
+    Shutdown,
+}
+
+/// Live status of the networks of the gateway, read from the WireGuard
+/// interfaces when requested.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct GatewayStatus {
+    /// Status of every network, by listen port
+    pub networks: BTreeMap<u16, NetworkStatus>,
+}
+
+/// Live status of a network.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct NetworkStatus {
+    /// WireGuard public key of the network
+    pub public_key: Pubkey,
+    /// Status of every peer, by public key
+    pub peers: BTreeMap<Pubkey, PeerStatus>,
+    /// Hash of the config last applied to this network, see
+    /// [`NetworkState::config_hash`]. Unset while a change to the network
+    /// is being applied, or if applying it failed.
+    pub config_hash: Option<String>,
+}
+
+/// Live status of a peer.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PeerStatus {
+    /// Whether the peer had a handshake recently enough to be connected
+    pub connected: bool,
+    /// Latest handshake, as UNIX timestamp
+    pub latest_handshake: Option<usize>,
+    /// Endpoint the peer is connecting from
+    pub endpoint: Option<SocketAddr>,
+    /// Bytes received from the peer
+    pub transfer_rx: usize,
+    /// Bytes sent to the peer
+    pub transfer_tx: usize,
+}
+
+/// How many of the configured peers of a network had a recent handshake,
+/// summed over the watchdog runs within a rolling window.
+///
+/// A network where only a few of its peers ever complete a handshake usually
+/// has a problem, such as a firewall, MTU or key mismatch.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct HandshakeRate {
+    /// Length of the window in seconds
+    pub window: usize,
+    /// Configured peers, summed over the watchdog runs in the window
+    pub peers: usize,
+    /// Peers with a recent handshake, summed over the watchdog runs in the
+    /// window
+    pub handshakes: usize,
+}
+
+impl HandshakeRate {
+    /// Ratio of peers with a recent handshake to configured peers, between
+    /// 0 and 1. Returns `None` if the network had no peers.
+    pub fn rate(&self) -> Option<f64> {
+        if self.peers == 0 {
+            None
+        } else {
+            Some(self.handshakes as f64 / self.peers as f64)
+        }
+    }
+}
+
+/// Kernel counters of a network interface.
+///
+/// Unlike the WireGuard transfer statistics, these include packets which were
+/// dropped or failed, which helps to diagnose packet loss.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct InterfaceStats {
+    pub rx_packets: usize,
+    pub rx_errors: usize,
+    pub rx_dropped: usize,
+    pub tx_packets: usize,
+    pub tx_errors: usize,
+    pub tx_dropped: usize,
+}
+
+/// Kernel counters of the interfaces of a network.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct NetworkInterfaceStats {
+    /// WireGuard interface
+    pub wireguard: InterfaceStats,
+    /// Veth interface inside of the network namespace
+    pub veth: InterfaceStats,
 }
 
 /// Represents the configuration state of one particular WireGuard network.
@@ -153,15 +603,352 @@ pub struct NetworkState {
     /// UDP port this network is reachable on
     #[serde(default)]
     pub listen_port: u16,
-    /// MTU (maximum packet size) for network.
-    #[serde(default = "default_mtu")]
-    pub mtu: usize,
+    /// MTU (maximum packet size) for network. If unset, the gateway's
+    /// default MTU is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<usize>,
     /// Subnet for this network.
     pub address: Vec<IpNet>,
     /// Configuration state for peers in this network
     pub peers: BTreeMap<Pubkey, PeerState>,
     /// Forwarding settings for this network
     pub proxy: BTreeMap<Url, Vec<SocketAddr>>,
+    /// Options for forwarding entries, by URL. Entries without options use
+    /// the defaults.
+    #[serde(default)]
+    pub proxy_options: BTreeMap<Url, ProxyOptions>,
+    /// Require every peer of this network to have a preshared key.
+    #[serde(default)]
+    pub require_psk: bool,
+    /// Persistent keepalive interval in seconds for peers of this network
+    /// which do not set their own. If unset, the gateway default is used.
+    #[serde(default)]
+    pub default_keepalive: Option<u16>,
+    /// Address families this network is reachable over, peer endpoints in
+    /// other families are rejected. Empty permits both.
+    #[serde(default)]
+    pub endpoint_families: Vec<AddressFamily>,
+    /// Assign an address out of the network's first subnet to peers without
+    /// allowed IPs.
+    #[serde(default)]
+    pub assign_addresses: bool,
+    /// Egress policy of traffic leaving this network, a tag which the
+    /// gateway maps to one of its routing tables. Without one, traffic does
+    /// not leave the network.
+    #[serde(default)]
+    pub egress_policy: Option<String>,
+}
+
+/// IP address family.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    /// Address family of a socket address.
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(_) => AddressFamily::Ipv4,
+            SocketAddr::V6(_) => AddressFamily::Ipv6,
+        }
+    }
+}
+
+impl NetworkState {
+    /// Hash of this network state, which the gateway reports in
+    /// [`NetworkStatus::config_hash`] once it has applied it. This is the
+    /// hex-encoded SHA-256 of the JSON serialization of the network state,
+    /// with fields in declaration order, maps sorted by key and unset
+    /// optional fields without a default left out. The gateway fills in the
+    /// listen port and the MTU before applying a network, so they have to
+    /// be set for the hash to match.
+    pub fn config_hash(&self) -> String {
+        // network states only have string-like map keys, so this cannot fail.
+        let json = serde_json::to_vec(self).unwrap();
+        Sha256::digest(&json)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Returns a view of this network state which is safe to share, with the
+    /// private key replaced by the public key and preshared keys redacted.
+    pub fn to_public(&self) -> PublicNetworkState {
+        PublicNetworkState {
+            public_key: self.private_key.pubkey(),
+            listen_port: self.listen_port,
+            mtu: self.mtu,
+            address: self.address.clone(),
+            peers: self
+                .peers
+                .iter()
+                .map(|(pubkey, peer)| (*pubkey, peer.to_public()))
+                .collect(),
+            proxy: self.proxy.clone(),
+            proxy_options: self.proxy_options.clone(),
+            require_psk: self.require_psk,
+            default_keepalive: self.default_keepalive,
+            endpoint_families: self.endpoint_families.clone(),
+            assign_addresses: self.assign_addresses,
+            egress_policy: self.egress_policy.clone(),
+        }
+    }
+
+    /// Truncate the allowed IPs of all peers, clearing host bits (so that
+    /// `10.0.0.5/24` becomes `10.0.0.0/24`), which is what WireGuard applies.
+    /// Returns the allowed IPs that were changed, with the peer they belong
+    /// to.
+    pub fn normalize(&mut self) -> Vec<(Pubkey, IpNet)> {
+        let mut changed = vec![];
+        for (pubkey, peer) in self.peers.iter_mut() {
+            for allowed_ip in peer.allowed_ips.iter_mut() {
+                let truncated = allowed_ip.trunc();
+                if truncated != *allowed_ip {
+                    changed.push((*pubkey, *allowed_ip));
+                    *allowed_ip = truncated;
+                }
+            }
+        }
+        changed
+    }
+
+    /// Assign a host address to every peer without allowed IPs, if
+    /// `assign_addresses` is set, and add it to the peer's allowed IPs.
+    ///
+    /// Peers keep the address in `previous` if it is still in the network's
+    /// first subnet and not used by another peer or the gateway itself.
+    /// Otherwise, they get the lowest free host address of that subnet. If
+    /// the subnet is full, this fails naming the first peer left without an
+    /// address. Returns the addresses of all peers which were assigned one.
+    pub fn assign_addresses(
+        &mut self,
+        previous: &BTreeMap<Pubkey, IpNet>,
+    ) -> Result<BTreeMap<Pubkey, IpNet>, ConfigError> {
+        let mut assigned = BTreeMap::new();
+        let subnet = match self.address.first() {
+            Some(address) if self.assign_addresses => address.trunc(),
+            _ => return Ok(assigned),
+        };
+
+        let mut taken: Vec<IpNet> = self
+            .peers
+            .values()
+            .flat_map(|peer| peer.allowed_ips.iter().cloned())
+            .collect();
+        taken.extend(
+            self.address
+                .iter()
+                .map(|address| IpNet::new(address.addr(), address.max_prefix_len()).unwrap()),
+        );
+        let is_free = |taken: &[IpNet], addr: &IpAddr| !taken.iter().any(|net| net.contains(addr));
+
+        // keep earlier assignments first, so that they are not handed out
+        // to other peers.
+        let mut unassigned = vec![];
+        for (pubkey, peer) in self.peers.iter() {
+            if !peer.allowed_ips.is_empty() {
+                continue;
+            }
+            match previous.get(pubkey) {
+                Some(net) if subnet.contains(net) && is_free(&taken, &net.addr()) => {
+                    taken.push(*net);
+                    assigned.insert(*pubkey, *net);
+                }
+                _ => unassigned.push(*pubkey),
+            }
+        }
+
+        for pubkey in unassigned {
+            let addr = subnet
+                .hosts()
+                .find(|addr| is_free(&taken, addr))
+                .ok_or(ConfigError::AddressesExhausted { peer: pubkey })?;
+            let net = IpNet::new(addr, subnet.max_prefix_len()).unwrap();
+            taken.push(net);
+            assigned.insert(pubkey, net);
+        }
+
+        for (pubkey, net) in &assigned {
+            if let Some(peer) = self.peers.get_mut(pubkey) {
+                peer.allowed_ips.push(*net);
+            }
+        }
+        Ok(assigned)
+    }
+
+    /// Check that this network state is valid and has at most `max_peers`
+    /// peers, returning the first problem found.
+    pub fn validate(&self, max_peers: usize) -> Result<(), ConfigError> {
+        if self.peers.len() > max_peers {
+            return Err(ConfigError::TooManyPeers {
+                peers: self.peers.len(),
+                max: max_peers,
+            });
+        }
+        if let Some(mtu) = self.mtu {
+            if !(MTU_MIN..=MTU_MAX).contains(&mtu) {
+                return Err(ConfigError::InvalidMtu { mtu });
+            }
+        }
+        if self.require_psk {
+            for (pubkey, peer) in &self.peers {
+                if peer.preshared_key.is_none() {
+                    return Err(ConfigError::MissingPresharedKey { peer: *pubkey });
+                }
+            }
+        }
+        if !self.endpoint_families.is_empty() {
+            for (pubkey, peer) in &self.peers {
+                if let Some(endpoint) = peer.endpoint {
+                    if !self
+                        .endpoint_families
+                        .contains(&AddressFamily::of(&endpoint))
+                    {
+                        return Err(ConfigError::UnreachableEndpoint {
+                            peer: *pubkey,
+                            endpoint,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Configuration state of a WireGuard network without any secrets, see
+/// [`NetworkState::to_public`].
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PublicNetworkState {
+    /// WireGuard public key
+    pub public_key: Pubkey,
+    /// UDP port this network is reachable on
+    pub listen_port: u16,
+    /// MTU (maximum packet size) for network.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<usize>,
+    /// Subnet for this network.
+    pub address: Vec<IpNet>,
+    /// Configuration state for peers in this network
+    pub peers: BTreeMap<Pubkey, PublicPeerState>,
+    /// Forwarding settings for this network
+    pub proxy: BTreeMap<Url, Vec<SocketAddr>>,
+    /// Options for forwarding entries, by URL
+    pub proxy_options: BTreeMap<Url, ProxyOptions>,
+    /// Whether every peer of this network needs a preshared key
+    pub require_psk: bool,
+    /// Persistent keepalive default for peers of this network
+    pub default_keepalive: Option<u16>,
+    /// Address families this network is reachable over
+    pub endpoint_families: Vec<AddressFamily>,
+    /// Whether peers without allowed IPs are assigned an address
+    pub assign_addresses: bool,
+    /// Egress policy of traffic leaving this network
+    pub egress_policy: Option<String>,
+}
+
+/// Configuration state of a peer without any secrets, see
+/// [`PeerState::to_public`].
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PublicPeerState {
+    /// Whether this peer has a preshared key
+    pub preshared_key: bool,
+    /// Allowed IP addresses of this peer
+    pub allowed_ips: Vec<IpNet>,
+    /// Last connected endpoint
+    pub endpoint: Option<SocketAddr>,
+    /// Persistent keepalive interval in seconds
+    pub persistent_keepalive: Option<u16>,
+    /// Bytes this peer may transfer per quota period
+    #[serde(default)]
+    pub quota_bytes: Option<usize>,
+}
+
+/// Options for a single forwarding entry of a network.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ProxyOptions {
+    /// Only allow clients from these subnets to reach the forwarded service,
+    /// matched against the address the client connects to the gateway from.
+    /// This is checked by the gateway's proxy before traffic enters the
+    /// network, independent of the peers' allowed IPs, and applies to HTTP,
+    /// HTTPS and SSH forwarding, which are all proxied by NGINX. Denied HTTP
+    /// requests get a 403 response, other denied connections are closed.
+    /// Empty allows everyone.
+    #[serde(default)]
+    pub allow_from: Vec<IpNet>,
+    /// Compression of responses, only applies to HTTP forwarding since
+    /// HTTPS traffic is passed through encrypted.
+    #[serde(default)]
+    pub compression: CompressionOptions,
+    /// Log requests to the forwarded service in the NGINX access log. Only
+    /// applies to HTTP forwarding, HTTPS forwarding is never logged.
+    #[serde(default = "default_log_access")]
+    pub log_access: bool,
+    /// Health checking of the backends of the forwarded service.
+    #[serde(default)]
+    pub health: HealthOptions,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        ProxyOptions {
+            allow_from: vec![],
+            compression: Default::default(),
+            log_access: default_log_access(),
+            health: Default::default(),
+        }
+    }
+}
+
+/// Health checking of forwarded backends. By default, NGINX's own defaults
+/// are used, which is passive checking with one allowed failure per ten
+/// seconds.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct HealthOptions {
+    /// Failed attempts after which a backend is considered unavailable,
+    /// zero disables passive checking
+    #[serde(default)]
+    pub max_fails: Option<usize>,
+    /// Seconds in which `max_fails` failed attempts mark a backend
+    /// unavailable, and for which it then stays unavailable
+    #[serde(default)]
+    pub fail_timeout: Option<usize>,
+    /// Actively check backends with the `health_check` directive. This needs
+    /// NGINX Plus (R9 or later) and only applies to HTTP forwarding, since
+    /// HTTPS forwarding picks its backend through a variable.
+    #[serde(default)]
+    pub active: bool,
+}
+
+/// Access logging is enabled by default.
+fn default_log_access() -> bool {
+    true
+}
+
+/// Compression of forwarded HTTP responses, disabled by default.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct CompressionOptions {
+    /// Enable gzip compression
+    #[serde(default)]
+    pub gzip: bool,
+    /// Enable brotli compression, requires the NGINX brotli module
+    #[serde(default)]
+    pub brotli: bool,
+    /// MIME types to compress in addition to `text/html`
+    #[serde(default)]
+    pub types: Vec<String>,
+    /// Minimum length of responses to compress, in bytes
+    #[serde(default)]
+    pub min_length: Option<usize>,
 }
 
 /// Represents the configuration state of one particular peer of a WireGuard network.
@@ -175,6 +962,27 @@ pub struct PeerState {
     pub allowed_ips: Vec<IpNet>,
     /// Last connected endpoint, used to resume talking to peer
     pub endpoint: Option<SocketAddr>,
+    /// Persistent keepalive interval in seconds, zero disables it. Takes
+    /// precedence over the network's `default_keepalive`.
+    #[serde(default)]
+    pub persistent_keepalive: Option<u16>,
+    /// Bytes this peer may transfer per quota period
+    #[serde(default)]
+    pub quota_bytes: Option<usize>,
+}
+
+impl PeerState {
+    /// Returns a view of this peer state which is safe to share, with the
+    /// preshared key redacted.
+    pub fn to_public(&self) -> PublicPeerState {
+        PublicPeerState {
+            preshared_key: self.preshared_key.is_some(),
+            allowed_ips: self.allowed_ips.clone(),
+            endpoint: self.endpoint,
+            persistent_keepalive: self.persistent_keepalive,
+            quota_bytes: self.quota_bytes,
+        }
+    }
 }
 
 /// Represents a single traffic item, consisting of received and sent bytes.
@@ -213,11 +1021,43 @@ impl AddAssign for Traffic {
     }
 }
 
+impl Sub for Traffic {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            rx: self.rx - rhs.rx,
+            tx: self.tx - rhs.tx,
+        }
+    }
+}
+
 impl Traffic {
     pub fn add(&mut self, other: &Traffic) {
         self.rx += other.rx;
         self.tx += other.tx;
     }
+
+    /// Subtract other traffic, returning `None` if either counter would
+    /// underflow. For WireGuard counters, this means that they were reset.
+    pub fn checked_sub(&self, other: &Traffic) -> Option<Traffic> {
+        Some(Traffic {
+            rx: self.rx.checked_sub(other.rx)?,
+            tx: self.tx.checked_sub(other.tx)?,
+        })
+    }
+}
+
+/// Traffic data kept by the gateway until the manager acknowledges it with
+/// [`GatewayRequest::TrafficAck`]. Cursors increase by one with every time
+/// slice, and start over when the gateway restarts.
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct BufferedTraffic {
+    /// Cursor of this time slice
+    pub cursor: u64,
+    /// Traffic data of this time slice
+    pub traffic: TrafficInfo,
 }
 
 /// Traffic data from the gateway for one particular time slice.
@@ -253,9 +1093,23 @@ impl TrafficInfo {
         self.stop_time = self.stop_time.max(time);
         network_traffic.add(device, time, traffic);
     }
+
+    /// Average receive and transmit rate over this time slice, in bytes per
+    /// second, or `None` if the time slice has no duration.
+    pub fn rate(&self) -> Option<(f64, f64)> {
+        let duration = self.stop_time.checked_sub(self.start_time)?;
+        if duration == 0 {
+            return None;
+        }
+        let duration = duration as f64;
+        Some((
+            self.traffic.rx as f64 / duration,
+            self.traffic.tx as f64 / duration,
+        ))
+    }
 }
 
-/// Traffic that occured within one particular network.
+/// Traffic that occurred within one particular network.
 #[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[derive(Serialize, Deserialize, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct NetworkTraffic {
@@ -292,3 +1146,335 @@ impl DeviceTraffic {
         self.times.insert(time, traffic);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(port: u16) -> NetworkState {
+        NetworkState {
+            private_key: Privkey::generate(),
+            listen_port: port,
+            mtu: None,
+            address: vec!["10.0.0.1/24".parse().unwrap()],
+            peers: BTreeMap::new(),
+            proxy: BTreeMap::new(),
+            proxy_options: BTreeMap::new(),
+            require_psk: false,
+            default_keepalive: None,
+            endpoint_families: vec![],
+            assign_addresses: false,
+            egress_policy: None,
+        }
+    }
+
+    #[test]
+    fn network_mtu_serialization() {
+        let mut network = network(51820);
+        let json = serde_json::to_value(&network).unwrap();
+        assert!(json.get("mtu").is_none());
+        assert_eq!(
+            serde_json::from_value::<NetworkState>(json).unwrap(),
+            network
+        );
+
+        network.mtu = Some(1420);
+        let json = serde_json::to_value(&network).unwrap();
+        assert_eq!(json["mtu"], 1420);
+        assert_eq!(
+            serde_json::from_value::<NetworkState>(json).unwrap(),
+            network
+        );
+    }
+
+    #[test]
+    fn traffic_checked_sub() {
+        let traffic = Traffic::new(100, 50);
+        assert_eq!(
+            traffic.checked_sub(&Traffic::new(40, 50)),
+            Some(Traffic::new(60, 0))
+        );
+        assert_eq!(traffic.checked_sub(&traffic), Some(Traffic::default()));
+    }
+
+    #[test]
+    fn traffic_checked_sub_underflow() {
+        let traffic = Traffic::new(100, 50);
+        assert_eq!(traffic.checked_sub(&Traffic::new(101, 0)), None);
+        assert_eq!(traffic.checked_sub(&Traffic::new(0, 51)), None);
+    }
+
+    #[test]
+    fn config_diff() {
+        let kept = network(1000);
+        let removed = network(1001);
+        let modified = network(1002);
+        let added = network(1003);
+        let mut config = GatewayConfig::default();
+        config.insert(1000, kept.clone());
+        config.insert(1001, removed);
+        config.insert(1002, modified.clone());
+
+        let mut other = GatewayConfig::default();
+        other.insert(1000, kept);
+        let modified = NetworkState {
+            mtu: Some(1420),
+            ..modified
+        };
+        other.insert(1002, modified.clone());
+        other.insert(1003, added.clone());
+
+        let diff = config.diff(&other);
+        let mut expected = GatewayConfigPartial::default();
+        expected.insert(1001, None);
+        expected.insert(1002, Some(modified));
+        expected.insert(1003, Some(added));
+        assert_eq!(diff, expected);
+
+        config.apply_partial(&diff);
+        assert_eq!(config, other);
+    }
+
+    #[test]
+    fn config_diff_unchanged() {
+        let mut config = GatewayConfig::default();
+        config.insert(1000, network(1000));
+        assert!(config.diff(&config.clone()).is_empty());
+    }
+
+    #[test]
+    fn network_config_hash() {
+        let network = network(51820);
+        let json = serde_json::to_string(&network).unwrap();
+        let parsed: NetworkState = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.config_hash(), network.config_hash());
+
+        let changed = NetworkState {
+            mtu: Some(1420),
+            ..network.clone()
+        };
+        assert_ne!(changed.config_hash(), network.config_hash());
+    }
+
+    #[test]
+    fn network_validate_mtu() {
+        let mut network = network(51820);
+        for mtu in [None, Some(MTU_MIN), Some(1420), Some(MTU_MAX)] {
+            network.mtu = mtu;
+            assert_eq!(network.validate(10), Ok(()));
+        }
+        for mtu in [0, MTU_MIN - 1, MTU_MAX + 1] {
+            network.mtu = Some(mtu);
+            assert_eq!(network.validate(10), Err(ConfigError::InvalidMtu { mtu }));
+        }
+    }
+
+    #[test]
+    fn config_validate_ports() {
+        let mut config = GatewayConfig::default();
+        config.insert(51820, network(51820));
+        // networks without a listen port listen on the port they are keyed by
+        config.insert(51821, network(0));
+        assert_eq!(config.validate(), Ok(()));
+
+        let mut zero = GatewayConfig::default();
+        zero.insert(0, network(0));
+        assert_eq!(zero.validate(), Err(ConfigError::ZeroPort));
+
+        let mut mismatched = GatewayConfig::default();
+        mismatched.insert(51820, network(51821));
+        assert_eq!(
+            mismatched.validate(),
+            Err(ConfigError::ListenPortMismatch {
+                port: 51820,
+                listen_port: 51821,
+            })
+        );
+    }
+
+    #[test]
+    fn partial_config_validate_ports() {
+        let mut partial = GatewayConfigPartial::default();
+        partial.insert(51820, Some(network(51820)));
+        partial.insert(51821, None);
+        assert_eq!(partial.validate(), Ok(()));
+
+        partial.insert(0, None);
+        assert_eq!(partial.validate(), Err(ConfigError::ZeroPort));
+    }
+
+    #[test]
+    fn config_check_overlaps() {
+        let mut first = network(1000);
+        first.address = vec!["10.0.0.1/16".parse().unwrap()];
+        let mut second = network(1001);
+        second.address = vec!["10.1.0.1/16".parse().unwrap()];
+        let mut config = GatewayConfig::default();
+        config.insert(1000, first);
+        config.insert(1001, second.clone());
+        assert_eq!(config.check_overlaps(), Ok(()));
+
+        // a subnet inside of another one overlaps it
+        let mut third = network(1002);
+        third.address = vec![
+            "fd00::1/64".parse().unwrap(),
+            "10.1.2.1/24".parse().unwrap(),
+        ];
+        config.insert(1002, third);
+        assert_eq!(
+            config.check_overlaps(),
+            Err(OverlapError {
+                first: 1001,
+                first_address: second.address[0],
+                second: 1002,
+                second_address: "10.1.2.1/24".parse().unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn traffic_info_rate() {
+        let network = Privkey::generate().pubkey();
+        let device = Privkey::generate().pubkey();
+        let mut traffic = TrafficInfo::new(1000);
+        assert_eq!(traffic.rate(), None);
+
+        traffic.add(network, device, 1010, Traffic::new(500, 100));
+        traffic.add(network, device, 1020, Traffic::new(500, 300));
+        assert_eq!(traffic.rate(), Some((50.0, 20.0)));
+    }
+
+    #[test]
+    fn traffic_info_rate_backwards() {
+        let mut traffic = TrafficInfo::new(1000);
+        traffic.stop_time = 990;
+        assert_eq!(traffic.rate(), None);
+    }
+
+    #[test]
+    fn gateway_response_status_serialization() {
+        let network = network(51820);
+        let peer = Privkey::generate().pubkey();
+        let mut status = GatewayStatus::default();
+        status.networks.insert(
+            network.listen_port,
+            NetworkStatus {
+                public_key: network.private_key.pubkey(),
+                peers: BTreeMap::from([(
+                    peer,
+                    PeerStatus {
+                        connected: true,
+                        latest_handshake: Some(1650000000),
+                        endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+                        transfer_rx: 1024,
+                        transfer_tx: 2048,
+                    },
+                )]),
+                config_hash: Some(network.config_hash()),
+            },
+        );
+        let response = GatewayResponse::Status(status);
+        let json = serde_json::to_string(&response).unwrap();
+        let parsed: GatewayResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, response);
+
+        let response = GatewayResponse::Status(GatewayStatus::default());
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json, serde_json::json!({ "Status": { "networks": {} } }));
+    }
+
+    #[test]
+    fn gateway_error_validation_roundtrip() {
+        let error = GatewayError::from(ConfigError::ZeroPort);
+        let response = GatewayResponse::Apply(Err(error.to_string()));
+        let json = serde_json::to_string(&response).unwrap();
+        let message = match serde_json::from_str::<GatewayResponse>(&json).unwrap() {
+            GatewayResponse::Apply(Err(message)) => message,
+            response => panic!("Unexpected response {response:?}"),
+        };
+        assert!(matches!(
+            GatewayError::from_apply(&message),
+            Some(GatewayError::Validation(reason)) if reason == ConfigError::ZeroPort.to_string()
+        ));
+        assert!(GatewayError::from_apply("Applying nginx configuration: failed").is_none());
+    }
+
+    fn peer(allowed_ips: &[&str]) -> PeerState {
+        PeerState {
+            preshared_key: None,
+            allowed_ips: allowed_ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            endpoint: None,
+            persistent_keepalive: None,
+            quota_bytes: None,
+        }
+    }
+
+    fn net(address: &str) -> IpNet {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn network_assign_addresses_reuse() {
+        let mut network = network(51820);
+        network.assign_addresses = true;
+        let key = Privkey::generate().pubkey();
+        network.peers.insert(key, peer(&[]));
+        let previous = BTreeMap::from([(key, net("10.0.0.7/32"))]);
+        assert_eq!(network.assign_addresses(&previous), Ok(previous));
+        assert_eq!(network.peers[&key].allowed_ips, vec![net("10.0.0.7/32")]);
+    }
+
+    #[test]
+    fn network_assign_addresses_previous_unusable() {
+        let mut network = network(51820);
+        network.assign_addresses = true;
+        let outside = Privkey::generate().pubkey();
+        let taken = Privkey::generate().pubkey();
+        network.peers.insert(outside, peer(&[]));
+        network.peers.insert(taken, peer(&[]));
+        network
+            .peers
+            .insert(Privkey::generate().pubkey(), peer(&["10.0.0.2/32"]));
+
+        // one previous address is outside of the subnet now, the other one
+        // was given to another peer
+        let previous = BTreeMap::from([(outside, net("10.0.1.2/32")), (taken, net("10.0.0.2/32"))]);
+        let assigned = network.assign_addresses(&previous).unwrap();
+        let mut addresses: Vec<IpNet> = assigned.values().cloned().collect();
+        addresses.sort();
+        assert_eq!(assigned.len(), 2);
+        assert_eq!(addresses, vec![net("10.0.0.3/32"), net("10.0.0.4/32")]);
+    }
+
+    #[test]
+    fn network_assign_addresses_skips_gateway() {
+        let mut network = network(51820);
+        network.assign_addresses = true;
+        let key = Privkey::generate().pubkey();
+        network.peers.insert(key, peer(&[]));
+
+        // the gateway's own address is neither kept nor handed out
+        let previous = BTreeMap::from([(key, net("10.0.0.1/32"))]);
+        let assigned = network.assign_addresses(&previous).unwrap();
+        assert_eq!(assigned, BTreeMap::from([(key, net("10.0.0.2/32"))]));
+    }
+
+    #[test]
+    fn network_assign_addresses_exhausted() {
+        let mut network = network(51820);
+        network.assign_addresses = true;
+        network.address = vec![net("10.0.0.1/30")];
+        network
+            .peers
+            .insert(Privkey::generate().pubkey(), peer(&[]));
+        network
+            .peers
+            .insert(Privkey::generate().pubkey(), peer(&[]));
+
+        // the subnet only has room for one peer besides the gateway
+        assert!(matches!(
+            network.assign_addresses(&BTreeMap::new()),
+            Err(ConfigError::AddressesExhausted { .. })
+        ));
+    }
+}
@@ -3,15 +3,16 @@ use fractal_gateway_client::*;
 use fractal_networking_wrappers::*;
 use futures::{SinkExt, StreamExt};
 use ipnet::{IpAdd, IpNet, Ipv4Net};
-use log::info;
+use log::{error, info};
 use rand::{prelude::SliceRandom, thread_rng, Rng};
 use std::collections::BTreeMap;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::ops::Range;
 use std::path::PathBuf;
 use structopt::StructOpt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::process::Command;
+use tokio::time::{Duration, Instant};
 use tokio_tungstenite::{accept_async, tungstenite::protocol::Message, WebSocketStream};
 use wireguard_keys::{Privkey, Pubkey};
 
@@ -32,11 +33,27 @@ pub struct Options {
         env = "INTEGRATION_GATEWAY"
     )]
     gateway: String,
+
+    /// Number of peers to verify concurrently
+    #[structopt(long, default_value = "8", env = "INTEGRATION_CONCURRENCY")]
+    concurrency: usize,
+
+    /// Watchdog interval of the gateway, in seconds
+    #[structopt(long, default_value = "60", env = "INTEGRATION_WATCHDOG")]
+    watchdog: u64,
 }
 
 const PORT_RANGE: Range<u16> = 50000..60000;
 const NETWORK_MTU: usize = 1420;
 
+/// Subnet of the network on a port. Every port gets its own subnet, because
+/// the gateway rejects configs whose networks overlap.
+fn network_address(port: u16) -> IpNet {
+    let index = port - PORT_RANGE.start;
+    let address = Ipv4Addr::new(10, (index / 256) as u8, (index % 256) as u8, 1);
+    Ipv4Net::new(address, 24).unwrap().into()
+}
+
 fn generate_config(
     size: usize,
     peers: Range<usize>,
@@ -48,14 +65,20 @@ fn generate_config(
     for _ in 0..size {
         let port = rng.gen_range(PORT_RANGE);
         let peers = rng.gen_range(peers.clone());
-        let address: IpNet = "10.0.0.1/8".parse().unwrap();
+        let address = network_address(port);
         let mut network = NetworkState {
             private_key: Privkey::generate(),
             listen_port: port,
-            mtu: NETWORK_MTU,
-            address: vec!["10.0.0.1/8".parse().unwrap()],
+            mtu: Some(NETWORK_MTU),
+            address: vec![address],
             peers: Default::default(),
             proxy: Default::default(),
+            proxy_options: Default::default(),
+            require_psk: false,
+            default_keepalive: None,
+            endpoint_families: vec![],
+            assign_addresses: false,
+            egress_policy: None,
         };
         for n in 0..peers {
             let address = match address.addr() {
@@ -72,6 +95,8 @@ fn generate_config(
                     allowed_ips: vec![address],
                     endpoint: None,
                     preshared_key: None,
+                    persistent_keepalive: None,
+                    quota_bytes: None,
                 },
             );
         }
@@ -158,6 +183,27 @@ async fn apply_partial_config(
     Err(anyhow!("Missing apply config response"))
 }
 
+async fn fetch_config(websocket: &mut WebSocketStream<TcpStream>) -> Result<GatewayConfig> {
+    websocket
+        .send(Message::Text(serde_json::to_string(&GatewayRequest::Config)?))
+        .await?;
+    while let Some(Ok(message)) = websocket.next().await {
+        match message {
+            Message::Text(value) => {
+                let value = serde_json::from_str(&value)?;
+                match value {
+                    GatewayResponse::Config(config) => {
+                        return Ok(config);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(anyhow!("Missing config response"))
+}
+
 async fn run_tests(global: &Global, websocket: &mut WebSocketStream<TcpStream>) -> Result<()> {
     info!("Applying empty config");
     let response = apply_config(websocket, Default::default()).await?;
@@ -220,6 +266,43 @@ async fn run_tests(global: &Global, websocket: &mut WebSocketStream<TcpStream>)
         verify_config(global, &config, &peer_keys).await?;
     }
 
+    info!("Reading back the running config");
+    assert_eq!(fetch_config(websocket).await?, config);
+
+    info!("Changing network address and making sure the old one is removed");
+    let old_config = generate_config(1, 1..3, &mut peer_keys);
+    let response = apply_config(websocket, old_config.clone()).await?;
+    assert!(response.is_ok());
+    verify_config(global, &old_config, &peer_keys).await?;
+    let mut new_config = old_config.clone();
+    for network in new_config.values_mut() {
+        network.address = vec!["10.255.0.1/8".parse().unwrap()];
+    }
+    let response = apply_config(websocket, new_config.clone()).await?;
+    assert!(response.is_ok());
+    verify_config(global, &new_config, &peer_keys).await?;
+    verify_old_config(global, &old_config, &peer_keys).await?;
+
+    info!("Reusing a port for a network with a new key in a single apply");
+    let old_config = generate_config(1, 1..3, &mut peer_keys);
+    let response = apply_config(websocket, old_config.clone()).await?;
+    assert!(response.is_ok());
+    verify_config(global, &old_config, &peer_keys).await?;
+    let mut new_config = old_config.clone();
+    for network in new_config.values_mut() {
+        network.private_key = Privkey::generate();
+    }
+    let response = apply_config(websocket, new_config.clone()).await?;
+    assert!(response.is_ok());
+    verify_config(global, &new_config, &peer_keys).await?;
+    verify_old_config(global, &old_config, &peer_keys).await?;
+
+    info!("Applying config with 10 networks and checking traffic accounting");
+    config = generate_config(10, 1..3, &mut peer_keys);
+    let response = apply_config(websocket, config.clone()).await?;
+    assert!(response.is_ok());
+    verify_traffic(global, websocket, &config, &peer_keys).await?;
+
     info!("Applying empty config");
     let response = apply_config(websocket, Default::default()).await?;
     assert!(response.is_ok());
@@ -227,8 +310,65 @@ async fn run_tests(global: &Global, websocket: &mut WebSocketStream<TcpStream>)
     Ok(())
 }
 
+/// Generate traffic on every network of the config and make sure that the
+/// gateway reports it. The watchdog only accounts for traffic between two of
+/// its runs, so this waits for it to pick up the networks before pinging
+/// and then listens for two more runs.
+async fn verify_traffic(
+    global: &Global,
+    websocket: &mut WebSocketStream<TcpStream>,
+    config: &GatewayConfig,
+    peer_keys: &BTreeMap<Pubkey, Privkey>,
+) -> Result<()> {
+    let interval = Duration::from_secs(global.options.watchdog);
+    tokio::time::sleep(interval + Duration::from_secs(5)).await;
+    verify_config(global, config, peer_keys).await?;
+
+    let mut traffic: BTreeMap<Pubkey, Traffic> = BTreeMap::new();
+    let deadline = Instant::now() + 2 * interval + Duration::from_secs(5);
+    while let Ok(Some(message)) = tokio::time::timeout_at(deadline, websocket.next()).await {
+        if let Message::Text(value) = message? {
+            if let GatewayResponse::Traffic(info) = serde_json::from_str(&value)? {
+                for (network, network_traffic) in info.networks {
+                    *traffic.entry(network).or_default() += network_traffic.traffic;
+                }
+            }
+        }
+    }
+
+    for (port, network) in config.iter() {
+        if network.peers.is_empty() {
+            continue;
+        }
+        let pubkey = network.private_key.pubkey();
+        let recorded = traffic.get(&pubkey).cloned().unwrap_or_default();
+        info!(
+            "Network {port} recorded {} bytes rx, {} bytes tx",
+            recorded.rx, recorded.tx
+        );
+        if recorded.rx == 0 || recorded.tx == 0 {
+            return Err(anyhow!("Missing traffic for network {port}: {recorded:?}"));
+        }
+        if recorded.rx + recorded.tx < TRAFFIC_MINIMUM {
+            return Err(anyhow!(
+                "Traffic for network {port} below keepalive minimum: {recorded:?}"
+            ));
+        }
+    }
+    Ok(())
+}
+
 pub const IP_PATH: &'static str = "ip";
 pub const PING_PATH: &'static str = "ping";
+
+/// Number of pings sent per peer. This is chosen so that the ping traffic
+/// alone exceeds [`TRAFFIC_MINIMUM`].
+pub const PING_COUNT: usize = 16;
+
+/// Traffic below which a network may be considered idle, mirroring the
+/// gateway's default `--traffic-minimum`.
+pub const TRAFFIC_MINIMUM: usize = 1024;
+
 async fn ping_host(netns: &str, host: IpAddr) -> Result<()> {
     let output = Command::new(IP_PATH)
         .arg("netns")
@@ -237,7 +377,7 @@ async fn ping_host(netns: &str, host: IpAddr) -> Result<()> {
         .arg(PING_PATH)
         .arg("-f")
         .arg("-c")
-        .arg("4")
+        .arg(PING_COUNT.to_string())
         .arg("-W")
         .arg("0.1")
         .arg(host.to_string())
@@ -251,79 +391,127 @@ async fn ping_host(netns: &str, host: IpAddr) -> Result<()> {
     }
 }
 
-async fn verify_config(
+/// Persistent keepalive of the peers used to verify networks.
+pub const PEER_KEEPALIVE: u16 = 25;
+
+/// Generate the WireGuard config for a peer connecting to a network.
+fn peer_config(
+    privkey: &Privkey,
+    network: &Pubkey,
+    endpoint: SocketAddr,
+    allowed_ips: &[IpNet],
+    keepalive: Option<u16>,
+) -> String {
+    let mut config = vec![
+        format!("[Interface]"),
+        format!("PrivateKey = {privkey}"),
+        String::new(),
+        format!("[Peer]"),
+        format!("PublicKey = {network}"),
+        format!("Endpoint = {endpoint}"),
+    ];
+    for allowed_ip in allowed_ips {
+        config.push(format!("AllowedIPs = {allowed_ip}"));
+    }
+    if let Some(keepalive) = keepalive {
+        config.push(format!("PersistentKeepalive = {keepalive}"));
+    }
+    config.join("\n")
+}
+
+/// Connect a peer to a network from its own network namespace and check
+/// whether the network address is reachable. The namespace name contains
+/// the port and the peer public key, so that it is unique even when peers
+/// are verified concurrently.
+async fn verify_peer(
     global: &Global,
-    config: &GatewayConfig,
+    port: u16,
+    network: &NetworkState,
+    pubkey: &Pubkey,
+    peer: &PeerState,
     peer_keys: &BTreeMap<Pubkey, Privkey>,
+    reachable: bool,
 ) -> Result<()> {
-    for (port, network) in config.iter() {
-        for (pubkey, peer) in network.peers.iter() {
-            let netns = format!("network-{port}-{}", pubkey.to_hex());
-            netns_add(&netns).await?;
-            wireguard_create(Some(&netns), "wg0").await?;
-            interface_up(Some(&netns), "wg0").await?;
-            let addr = match peer.allowed_ips[0] {
-                IpNet::V4(ipv4net) => IpNet::V4(Ipv4Net::new(ipv4net.addr(), 8)?),
-                _ => unreachable!(),
-            };
-            addr_add(Some(&netns), "wg0", addr).await?;
-            let config = [
-                format!("[Interface]"),
-                format!("PrivateKey = {}", peer_keys.get(pubkey).unwrap()),
-                String::new(),
-                format!("[Peer]"),
-                format!("PublicKey = {}", network.private_key.pubkey()),
-                format!("Endpoint = {}:{port}", global.gateway),
-                format!("AllowedIPs = {}", network.address[0]),
-                format!("PersistentKeepalive = 25"),
-            ]
-            .join("\n");
-            netns_write_file(&netns, &PathBuf::from("wireguard/wg0.conf"), &config).await?;
-            wireguard_syncconf(&netns, "wg0").await?;
-            ping_host(&netns, network.address[0].addr()).await?;
-            netns_del(&netns).await?;
+    let netns = format!("network-{port}-{}", pubkey.to_hex());
+    netns_add(&netns).await?;
+    let result = async {
+        wireguard_create(Some(&netns), "wg0").await?;
+        interface_up(Some(&netns), "wg0").await?;
+        let addr = match peer.allowed_ips[0] {
+            IpNet::V4(ipv4net) => IpNet::V4(Ipv4Net::new(ipv4net.addr(), 8)?),
+            _ => unreachable!(),
+        };
+        addr_add(Some(&netns), "wg0", addr).await?;
+        let config = peer_config(
+            peer_keys.get(pubkey).unwrap(),
+            &network.private_key.pubkey(),
+            SocketAddr::new(global.gateway, port),
+            &network.address[..1],
+            Some(PEER_KEEPALIVE),
+        );
+        netns_write_file(&netns, &PathBuf::from("wireguard/wg0.conf"), &config).await?;
+        wireguard_syncconf(&netns, "wg0").await?;
+        let result = ping_host(&netns, network.address[0].addr()).await;
+        match (reachable, result) {
+            (true, result) => result,
+            (false, Ok(())) => Err(anyhow!("Network {port} is reachable from peer {pubkey}")),
+            (false, Err(_)) => Ok(()),
         }
     }
-    Ok(())
+    .await;
+    netns_del(&netns).await?;
+    result
 }
 
-async fn verify_old_config(
+/// Verify all peers of a config, running up to `concurrency` verifications
+/// at once. All failures are collected rather than aborting on the first.
+async fn verify_peers(
     global: &Global,
     config: &GatewayConfig,
     peer_keys: &BTreeMap<Pubkey, Privkey>,
+    reachable: bool,
 ) -> Result<()> {
-    for (port, network) in config.iter() {
-        for (pubkey, peer) in network.peers.iter() {
-            let netns = format!("network-{port}-{}", pubkey.to_hex());
-            netns_add(&netns).await?;
-            wireguard_create(Some(&netns), "wg0").await?;
-            interface_up(Some(&netns), "wg0").await?;
-            let addr = match peer.allowed_ips[0] {
-                IpNet::V4(ipv4net) => IpNet::V4(Ipv4Net::new(ipv4net.addr(), 8)?),
-                _ => unreachable!(),
-            };
-            addr_add(Some(&netns), "wg0", addr).await?;
-            let config = [
-                format!("[Interface]"),
-                format!("PrivateKey = {}", peer_keys.get(pubkey).unwrap()),
-                String::new(),
-                format!("[Peer]"),
-                format!("PublicKey = {}", network.private_key.pubkey()),
-                format!("Endpoint = {}:{port}", global.gateway),
-                format!("AllowedIPs = {}", network.address[0]),
-                format!("PersistentKeepalive = 25"),
-            ]
-            .join("\n");
-            netns_write_file(&netns, &PathBuf::from("wireguard/wg0.conf"), &config).await?;
-            wireguard_syncconf(&netns, "wg0").await?;
-            let result = ping_host(&netns, network.address[0].addr()).await;
-            if result.is_ok() {
-                return Err(anyhow::anyhow!("Network is reachable"));
-            }
-            netns_del(&netns).await?;
-        }
+    let failures: Vec<anyhow::Error> =
+        futures::stream::iter(config.iter().flat_map(|(port, network)| {
+            network
+                .peers
+                .iter()
+                .map(move |(pubkey, peer)| (*port, network, pubkey, peer))
+        }))
+        .map(|(port, network, pubkey, peer)| {
+            verify_peer(global, port, network, pubkey, peer, peer_keys, reachable)
+        })
+        .buffer_unordered(global.options.concurrency.max(1))
+        .filter_map(|result| async move { result.err() })
+        .collect()
+        .await;
+    if failures.is_empty() {
+        return Ok(());
     }
-    Ok(())
+    for failure in &failures {
+        error!("Verification failed: {failure:#}");
+    }
+    Err(anyhow!(
+        "Verification failed for {} peers: {:#}",
+        failures.len(),
+        failures[0]
+    ))
+}
+
+async fn verify_config(
+    global: &Global,
+    config: &GatewayConfig,
+    peer_keys: &BTreeMap<Pubkey, Privkey>,
+) -> Result<()> {
+    verify_peers(global, config, peer_keys, true).await
+}
+
+async fn verify_old_config(
+    global: &Global,
+    config: &GatewayConfig,
+    peer_keys: &BTreeMap<Pubkey, Privkey>,
+) -> Result<()> {
+    verify_peers(global, config, peer_keys, false).await
 }
 
 struct Global {
@@ -0,0 +1,73 @@
+//! Audit log of the changes requested by the manager. Entries are kept as
+//! JSON lines in the file given by `--audit-log`, and entries older than the
+//! retention period are dropped whenever a new entry is recorded.
+
+use crate::{Global, Options};
+use anyhow::{Context, Result};
+use fractal_gateway_client::{AuditEntry, AuditOperation};
+use log::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Read the audit log entries within the retention period, oldest first.
+pub async fn read(options: &Options) -> Result<Vec<AuditEntry>> {
+    let path = match &options.audit_log {
+        Some(path) if path.exists() => path,
+        _ => return Ok(vec![]),
+    };
+    let data = tokio::fs::read_to_string(path)
+        .await
+        .context("Reading audit log")?;
+    let cutoff = SystemTime::now()
+        .checked_sub(options.audit_retention)
+        .unwrap_or(UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as usize;
+    let mut entries = vec![];
+    for line in data.lines().filter(|line| !line.is_empty()) {
+        match serde_json::from_str::<AuditEntry>(line) {
+            Ok(entry) if entry.time >= cutoff => entries.push(entry),
+            Ok(_) => {}
+            Err(e) => warn!("Skipping invalid audit log entry: {e}"),
+        }
+    }
+    Ok(entries)
+}
+
+/// Record a change in the audit log, if one is kept.
+pub async fn record(
+    global: &Global,
+    operation: AuditOperation,
+    error: Option<String>,
+) -> Result<()> {
+    let options = global.options();
+    let path = match &options.audit_log {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let mut entries = read(options).await?;
+    entries.push(AuditEntry {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as usize,
+        manager: global.manager.to_string(),
+        identity: options.identity.clone(),
+        operation,
+        error,
+    });
+
+    let mut data = String::new();
+    for entry in &entries {
+        data.push_str(&serde_json::to_string(entry)?);
+        data.push('\n');
+    }
+    let partial = path.with_extension("partial");
+    tokio::fs::write(&partial, data)
+        .await
+        .context("Writing audit log")?;
+    tokio::fs::rename(&partial, path)
+        .await
+        .context("Writing audit log")?;
+    Ok(())
+}
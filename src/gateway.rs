@@ -1,31 +1,55 @@
 use crate::types::*;
+use crate::util;
+use crate::watchdog::WIREGUARD_HANDSHAKE_TIMEOUT;
 use crate::Global;
 use crate::Options;
 use anyhow::anyhow;
 use anyhow::{Context, Result};
-use fractal_gateway_client::{GatewayConfig, GatewayConfigPartial, NetworkState};
-use fractal_networking_wrappers::*;
-use ipnet::{IpNet, Ipv4Net};
+use fractal_gateway_client::{
+    AddressAssignments, GatewayConfig, GatewayConfigPartial, GatewayError, GatewayEvent,
+    GatewayMtuRejectedEvent, GatewayStatus, NetworkState, NetworkStatus, PeerState, PeerStatus,
+};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use lazy_static::lazy_static;
 use log::*;
 use regex::Regex;
+use serde::de::DeserializeOwned;
 use std::borrow::Cow;
-use std::collections::HashSet;
-use std::net::Ipv4Addr;
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tera::Tera;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use wireguard_keys::{Privkey, Pubkey, Secret};
 
 /// Name of the bride network interface to use
 const BRIDGE_INTERFACE: &'static str = "ensbr0";
 
-/// Path of the NGINX modules configuration
-const NGINX_MODULE_PATH: &'static str = "/etc/nginx/modules-enabled/gateway.conf";
+/// Priority of the routing rules for egress policies. All rules with this
+/// priority are managed by the gateway.
+const EGRESS_RULE_PRIORITY: u32 = 10000;
 
-/// Path of the NGINX site configuration
-const NGINX_SITE_PATH: &'static str = "/etc/nginx/sites-enabled/gateway.conf";
+/// Path of the WireGuard kernel module in sysfs, exists if it is loaded
+const WIREGUARD_MODULE_PATH: &'static str = "/sys/module/wireguard";
+
+/// How often to check whether a removed network's interfaces are gone
+const TEARDOWN_RETRIES: usize = 50;
+
+/// Interval between checks whether a removed network's interfaces are gone
+const TEARDOWN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Delay before the first retry of a failed wireguard syncconf, doubled for
+/// every further retry
+const SYNCCONF_BACKOFF: Duration = Duration::from_millis(100);
 
 lazy_static! {
     pub static ref BRIDGE_NET: Ipv4Net = Ipv4Net::new(Ipv4Addr::new(172, 99, 0, 1), 16).unwrap();
+    pub static ref BRIDGE_NET6: Ipv6Net =
+        Ipv6Net::new(Ipv6Addr::new(0xfd00, 0x99, 0, 0, 0, 0, 0, 1), 64).unwrap();
     pub static ref TERA_TEMPLATES: Tera = {
         let mut tera = Tera::default();
         tera.add_raw_templates([
@@ -33,6 +57,10 @@ lazy_static! {
                 "iptables.save",
                 include_str!("../templates/iptables.save.tera"),
             ),
+            (
+                "ip6tables.save",
+                include_str!("../templates/ip6tables.save.tera"),
+            ),
             ("nginx.conf", include_str!("../templates/nginx.conf.tera")),
             (
                 "sites.nginx.conf",
@@ -46,125 +74,711 @@ lazy_static! {
 }
 
 /// Called on a fresh start, initialize NGINX config if needed.
-pub async fn startup(options: &Options) -> Result<()> {
-    let module_path = Path::new(NGINX_MODULE_PATH);
-    if !module_path.is_file() {
-        for (url, socket) in &options.custom_forwarding {
+pub async fn startup(global: &Global) -> Result<()> {
+    if !global.options().nginx_module_path.is_file() {
+        for (url, socket) in &global.options().custom_forwarding {
             info!("Custom forwarding: {} => {:?}", url.to_string(), socket);
         }
-        apply_nginx(&[], options).await?;
+        apply_nginx(global, &[]).await?;
     }
 
     Ok(())
 }
 
+/// Detect whether WireGuard is available as kernel module or in userspace,
+/// failing if neither is present.
+pub async fn check_wireguard(paths: &util::Paths) -> Result<WireguardInfo> {
+    let tools = util::wireguard_tools_version(paths).await?;
+
+    let module = Path::new(WIREGUARD_MODULE_PATH);
+    if !module.is_dir() {
+        if let Err(e) = util::modprobe(paths, "wireguard").await {
+            warn!("Unable to load wireguard kernel module: {e:#}");
+        }
+    }
+
+    let wireguard = if module.is_dir() {
+        let version = tokio::fs::read_to_string(module.join("version"))
+            .await
+            .ok()
+            .map(|version| version.trim().to_string());
+        WireguardInfo {
+            mode: WireguardMode::Kernel,
+            version,
+            tools,
+        }
+    } else if let Ok(version) = util::wireguard_go_version(paths).await {
+        WireguardInfo {
+            mode: WireguardMode::Userspace,
+            version: Some(version),
+            tools,
+        }
+    } else {
+        return Err(anyhow!(
+            "WireGuard is not available: kernel module not loaded and wireguard-go not installed"
+        ));
+    };
+
+    info!(
+        "Using WireGuard {:?} version {}, {}",
+        wireguard.mode,
+        wireguard.version.as_deref().unwrap_or("unknown"),
+        wireguard.tools
+    );
+    Ok(wireguard)
+}
+
 /// Given a new state, do whatever needs to be done to get the system in that
-/// state.
-pub async fn apply(global: &Global, config: &GatewayConfig) -> Result<()> {
+/// state. Keys of the config are masked in the returned error.
+pub async fn apply(global: &Global, config: &GatewayConfig) -> Result<AddressAssignments> {
+    apply_config(global, config)
+        .await
+        .map_err(|error| redact_error(error, config.values()))
+}
+
+async fn apply_config(global: &Global, config: &GatewayConfig) -> Result<AddressAssignments> {
     info!("Applying new state");
+    config.validate().map_err(invalid)?;
+    config.check_overlaps().map_err(invalid)?;
+    let mut config = config.clone();
+    for (port, network) in config.iter_mut() {
+        normalize_network(global.options(), *port, network);
+    }
+
+    for (port, network) in config.iter() {
+        network
+            .validate(global.options().max_peers)
+            .with_context(|| format!("Validating network {port}"))
+            .map_err(invalid)?;
+    }
+    check_veth_addresses(config.keys()).map_err(invalid)?;
+    check_bridge_overlaps(config.iter()).map_err(invalid)?;
+    check_egress_policies(global.options(), config.iter()).map_err(invalid)?;
+    forwarding(global.options(), config.values()).map_err(invalid)?;
+
     let mut state = global.lock().lock().await;
-    *state = config.clone();
+    let mut assignments = global.assignments();
+    let assigned = assign_addresses(&mut assignments, config.iter_mut()).map_err(invalid)?;
+    assignments.retain(|port, _| config.contains_key(port));
+    marker_write(global.options(), &config).await?;
+    let previous = std::mem::replace(&mut *state, config.clone());
+    let prefix = &global.options().netns_prefix;
 
     // turn config into list of network states
-    let state: Vec<NetworkState> = config
-        .iter()
-        .map(|(port, state)| {
-            let mut state = state.clone();
-            state.listen_port = *port;
-            state
-        })
-        .collect();
+    let state: Vec<NetworkState> = config.values().cloned().collect();
 
     // set up bridge
-    apply_bridge(BRIDGE_INTERFACE, &vec![(*BRIDGE_NET).into()])
+    apply_bridge(global.paths(), BRIDGE_INTERFACE, &bridge_addresses(&state))
         .await
         .context("Creating bridge interface")?;
 
     // find out which netns exist right now
-    let netns_list: HashSet<String> = netns_list()
+    let netns_list: HashSet<String> = util::netns_list(global.paths())
         .await?
         .into_iter()
-        .map(|netns| netns.name)
         .collect();
 
     // find out which we are expecting to exist
-    let netns_expected: HashSet<String> =
-        state.iter().map(|network| network.netns_name()).collect();
+    let netns_expected: HashSet<String> = state
+        .iter()
+        .map(|network| network.netns_name(prefix))
+        .collect();
 
-    // ones that exist but shouldn't, we delete them.
+    // ones that exist but shouldn't, we delete them. namespaces without our
+    // prefix may belong to other gateway instances, so they are left alone.
     for netns in netns_list.difference(&netns_expected) {
-        if netns.starts_with(NETNS_PREFIX) {
-            netns_del(&netns)
+        if let Some(port) = parse_netns_port(prefix, netns) {
+            remove_network(global, netns, &format!("{VETH_PREFIX}{port}"))
                 .await
                 .context("Removing surplus network namespace")?;
         }
     }
+    for port in previous.keys() {
+        if !config.contains_key(port) {
+            global.set_config_hash(*port, None);
+        }
+    }
+
+    // ones whose port is reused by a different network are deleted too, so
+    // that the new network does not inherit any of their state.
+    for network in &state {
+        let replaced = previous
+            .get(&network.listen_port)
+            .map(|previous| previous.private_key != network.private_key)
+            .unwrap_or(false);
+        let netns = network.netns_name(prefix);
+        if replaced && netns_list.contains(&netns) {
+            remove_network(global, &netns, &network.veth_name())
+                .await
+                .context("Removing replaced network namespace")?;
+        }
+    }
 
+    // only once all deletions are done, create or update networks. networks
+    // which change have no config hash until the whole apply succeeded.
     for network in &state {
+        if previous.get(&network.listen_port) != Some(network) {
+            global.set_config_hash(network.listen_port, None);
+        }
         apply_network(global, network).await?;
     }
 
-    apply_nginx(&state, global.options())
+    apply_nginx(global, &state)
         .await
         .context("Applying nginx configuration")?;
 
-    Ok(())
+    apply_egress_rules(global, &state)
+        .await
+        .context("Applying egress routing rules")?;
+
+    for network in &state {
+        global.set_config_hash(network.listen_port, Some(network.config_hash()));
+    }
+    marker_clear(global.options()).await?;
+    global.set_assignments(assignments).await?;
+    global.applied();
+    Ok(assigned)
 }
 
-/// Apply a partial config, this is only a diff.
-pub async fn apply_partial(global: &Global, config: &GatewayConfigPartial) -> Result<()> {
+/// Apply a partial config, this is only a diff. Keys of the config are
+/// masked in the returned error.
+pub async fn apply_partial(
+    global: &Global,
+    config: &GatewayConfigPartial,
+) -> Result<AddressAssignments> {
+    apply_partial_config(global, config)
+        .await
+        .map_err(|error| redact_error(error, config.values().flatten()))
+}
+
+async fn apply_partial_config(
+    global: &Global,
+    config: &GatewayConfigPartial,
+) -> Result<AddressAssignments> {
     info!("Applying new partial state");
+    config.validate().map_err(invalid)?;
+    let mut config = config.clone();
+    for (port, network) in config.iter_mut() {
+        if let Some(network) = network {
+            normalize_network(global.options(), *port, network);
+        }
+    }
+
+    for (port, network) in config.iter() {
+        if let Some(network) = network {
+            network
+                .validate(global.options().max_peers)
+                .with_context(|| format!("Validating network {port}"))
+                .map_err(invalid)?;
+        }
+    }
+
     let mut state = global.lock().lock().await;
+    let mut assignments = global.assignments();
+    let assigned = assign_addresses(
+        &mut assignments,
+        config
+            .iter_mut()
+            .filter_map(|(port, network)| network.as_mut().map(|network| (port, network))),
+    )
+    .map_err(invalid)?;
+    for (port, network) in config.iter() {
+        if network.is_none() {
+            assignments.remove(port);
+        }
+    }
+
+    // the marker holds the full config this partial apply results in.
+    let mut pending = state.clone();
+    for (port, network) in config.iter() {
+        match network {
+            Some(network) => pending.insert(*port, network.clone()),
+            None => pending.remove(port),
+        };
+    }
+    pending.check_overlaps().map_err(invalid)?;
+    check_veth_addresses(pending.keys()).map_err(invalid)?;
+    check_bridge_overlaps(pending.iter()).map_err(invalid)?;
+    check_egress_policies(global.options(), pending.iter()).map_err(invalid)?;
+    forwarding(global.options(), pending.values()).map_err(invalid)?;
+    marker_write(global.options(), &pending).await?;
 
     // set up bridge
-    apply_bridge(BRIDGE_INTERFACE, &vec![(*BRIDGE_NET).into()])
-        .await
-        .context("Creating bridge interface")?;
+    apply_bridge(
+        global.paths(),
+        BRIDGE_INTERFACE,
+        &bridge_addresses(pending.values()),
+    )
+    .await
+    .context("Creating bridge interface")?;
 
     // find out which netns exist right now
-    let netns_list: HashSet<String> = netns_list()
+    let netns_list: HashSet<String> = util::netns_list(global.paths())
         .await?
         .into_iter()
-        .map(|netns| netns.name)
         .collect();
 
+    // process all deletions first, including networks whose port is reused
+    // by a different network, so that creations do not collide with them.
     for (port, config) in config.iter() {
-        match config {
-            None => {
-                state.remove(port);
-                let netns = format!("{NETNS_PREFIX}{port}");
-                if netns_list.contains(&netns) {
-                    netns_del(&netns).await?;
+        let remove = match config {
+            None => true,
+            Some(network) => state
+                .get(port)
+                .map(|previous| previous.private_key != network.private_key)
+                .unwrap_or(false),
+        };
+        let netns = format!("{}{port}", global.options().netns_prefix);
+        if remove && netns_list.contains(&netns) {
+            remove_network(global, &netns, &format!("{VETH_PREFIX}{port}")).await?;
+        }
+        if config.is_none() {
+            state.remove(port);
+            global.set_config_hash(*port, None);
+        }
+    }
+
+    for (port, config) in config.iter() {
+        if let Some(network) = config {
+            global.set_config_hash(*port, None);
+            apply_network(global, network).await?;
+            state.insert(*port, network.clone());
+        }
+    }
+
+    let networks: Vec<_> = state.iter().map(|(_port, state)| state.clone()).collect();
+
+    apply_nginx(global, &networks)
+        .await
+        .context("Applying nginx configuration")?;
+
+    apply_egress_rules(global, &networks)
+        .await
+        .context("Applying egress routing rules")?;
+
+    for (port, network) in config.iter() {
+        if let Some(network) = network {
+            global.set_config_hash(*port, Some(network.config_hash()));
+        }
+    }
+    marker_clear(global.options()).await?;
+    global.set_assignments(assignments).await?;
+    global.applied();
+    Ok(assigned)
+}
+
+/// Read the live status of all networks of this gateway from their WireGuard
+/// interfaces.
+pub async fn status(global: &Global) -> Result<GatewayStatus> {
+    let now = SystemTime::now();
+    let mut status = GatewayStatus::default();
+    for (port, stats) in all_stats(global).await? {
+        status
+            .networks
+            .insert(port, network_status(global, port, &stats, now));
+    }
+    Ok(status)
+}
+
+/// Build the status of a network from the stats of its WireGuard interface.
+/// Peers without a handshake within the handshake timeout are reported as
+/// disconnected.
+pub fn network_status(
+    global: &Global,
+    port: u16,
+    stats: &NetworkStats,
+    now: SystemTime,
+) -> NetworkStatus {
+    let peers = stats
+        .peers()
+        .iter()
+        .map(|peer| {
+            let connected = peer
+                .latest_handshake
+                .and_then(|handshake| now.duration_since(handshake).ok())
+                .map(|age| age.as_secs() <= WIREGUARD_HANDSHAKE_TIMEOUT)
+                .unwrap_or(false);
+            let latest_handshake = peer.latest_handshake.map(|handshake| {
+                handshake
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs() as usize
+            });
+            let status = PeerStatus {
+                connected,
+                latest_handshake,
+                endpoint: peer.endpoint,
+                transfer_rx: peer.transfer_rx,
+                transfer_tx: peer.transfer_tx,
+            };
+            (peer.public_key, status)
+        })
+        .collect();
+    NetworkStatus {
+        public_key: stats.public_key,
+        peers,
+        config_hash: global.config_hash(port),
+    }
+}
+
+/// Fetch the wireguard stats of every network running on this host, by
+/// listen port.
+pub async fn all_stats(global: &Global) -> Result<BTreeMap<u16, NetworkStats>> {
+    let mut networks = BTreeMap::new();
+    for netns in util::netns_list(global.paths()).await? {
+        let port = match parse_netns_port(&global.options().netns_prefix, &netns) {
+            Some(port) => port,
+            None => continue,
+        };
+        let wgif = format!("{WIREGUARD_PREFIX}{port}");
+        let stats = util::wireguard_dump(global.paths(), &netns, &wgif)
+            .await
+            .with_context(|| format!("Fetching wireguard stats of network {port}"))?;
+        networks.insert(port, stats);
+    }
+    Ok(networks)
+}
+
+/// Reconstruct a best-effort config from the networks running on this host,
+/// for when the manager lost its records of this gateway.
+///
+/// The private key, addresses and MTU of every network and the preshared
+/// keys, allowed IPs, endpoints and keepalives of its peers are read from the
+/// live WireGuard interfaces. Forwarding cannot be recovered, since the NGINX
+/// and iptables configs do not record which URL a port mapping belongs to, so
+/// `proxy` is left empty. Network settings that only affect validation or
+/// defaults (such as `require_psk` or quotas) are not recovered either.
+pub async fn dump_from_host(options: &Options) -> Result<GatewayConfig> {
+    let limit = Arc::new(Semaphore::new(options.networking_limit.max(1)));
+    let paths = options.paths(limit);
+    let mut config = GatewayConfig::default();
+    for netns in util::netns_list(&paths).await? {
+        let port = match parse_netns_port(&options.netns_prefix, &netns) {
+            Some(port) => port,
+            None => continue,
+        };
+        let wgif = format!("{WIREGUARD_PREFIX}{port}");
+        let stats = util::wireguard_dump(&paths, &netns, &wgif).await?;
+        let mtu = util::interface_show(&paths, Some(&netns), &wgif).await?.mtu;
+        let address = util::addr_list(&paths, Some(&netns), &wgif).await?;
+        let peers = stats
+            .peers()
+            .iter()
+            .map(|peer| {
+                // keepalive is off if the peer had it set to zero.
+                let keepalive = u16::try_from(peer.persistent_keepalive.unwrap_or(0))
+                    .with_context(|| format!("Invalid keepalive of peer {}", peer.public_key))?;
+                let state = PeerState {
+                    preshared_key: peer.preshared_key.clone(),
+                    allowed_ips: peer.allowed_ips.clone(),
+                    endpoint: peer.endpoint,
+                    persistent_keepalive: Some(keepalive),
+                    quota_bytes: None,
+                };
+                Ok((peer.public_key, state))
+            })
+            .collect::<Result<_>>()?;
+        config.insert(
+            port,
+            NetworkState {
+                private_key: stats.private_key.clone(),
+                listen_port: port,
+                mtu,
+                address,
+                peers,
+                proxy: Default::default(),
+                proxy_options: Default::default(),
+                require_psk: false,
+                default_keepalive: None,
+                endpoint_families: vec![],
+                assign_addresses: false,
+                egress_policy: None,
+            },
+        );
+    }
+    Ok(config)
+}
+
+/// Assign addresses to peers of networks which ask for it, reusing and
+/// updating the earlier `assignments`. Returns the addresses assigned in these
+/// networks.
+fn assign_addresses<'a>(
+    assignments: &mut AddressAssignments,
+    networks: impl Iterator<Item = (&'a u16, &'a mut NetworkState)>,
+) -> Result<AddressAssignments> {
+    let mut assigned = AddressAssignments::new();
+    for (port, network) in networks {
+        let previous = assignments.remove(port).unwrap_or_default();
+        let peers = network
+            .assign_addresses(&previous)
+            .with_context(|| format!("Assigning addresses in network {port}"))?;
+        for (peer, address) in &peers {
+            if previous.get(peer) != Some(address) {
+                info!("Network {port}: assigned {address} to peer {peer}");
+            }
+        }
+        if !peers.is_empty() {
+            assignments.insert(*port, peers.clone());
+            assigned.insert(*port, peers);
+        }
+    }
+    Ok(assigned)
+}
+
+/// Make sure that the veth addresses derived from the listen ports of the
+/// networks are host addresses of the bridge network, distinct from the
+/// bridge's own address and from each other.
+fn check_veth_addresses<'a>(ports: impl Iterator<Item = &'a u16>) -> Result<()> {
+    let mut used: BTreeMap<Ipv4Addr, u16> = BTreeMap::new();
+    for port in ports {
+        let addr = veth_address(*port);
+        if !BRIDGE_NET.contains(&addr)
+            || addr == BRIDGE_NET.network()
+            || addr == BRIDGE_NET.broadcast()
+        {
+            return Err(anyhow!(
+                "Network {port} has veth address {addr} outside of the hosts of bridge network {}",
+                BRIDGE_NET.trunc()
+            ));
+        }
+        if addr == BRIDGE_NET.addr() {
+            return Err(anyhow!(
+                "Network {port} has veth address {addr}, which is the bridge's address"
+            ));
+        }
+        if let Some(other) = used.insert(addr, *port) {
+            return Err(anyhow!(
+                "Networks {other} and {port} have the same veth address {addr}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Make sure that the subnets of networks do not overlap the bridge networks.
+/// Traffic of a network towards the bridge is routed out of its veth
+/// interface, which would not happen for addresses that are in its subnet.
+fn check_bridge_overlaps<'a>(
+    networks: impl Iterator<Item = (&'a u16, &'a NetworkState)>,
+) -> Result<()> {
+    let bridges = [
+        IpNet::from(BRIDGE_NET.trunc()),
+        IpNet::from(BRIDGE_NET6.trunc()),
+    ];
+    for (port, network) in networks {
+        for address in &network.address {
+            for bridge in &bridges {
+                if address.contains(bridge) || bridge.contains(address) {
+                    return Err(anyhow!(
+                        "Network {port} has subnet {address}, which overlaps bridge network {bridge}"
+                    ));
                 }
             }
-            Some(network) => {
-                apply_network(global, network).await?;
-                state.insert(*port, network.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Write the apply marker, recording the config that is about to be applied.
+/// It is only removed once applying succeeds, so if it exists on startup the
+/// gateway crashed or failed while changing the host.
+async fn marker_write(options: &Options, config: &GatewayConfig) -> Result<()> {
+    if let Some(path) = &options.apply_marker {
+        // the config contains private keys, so keep it private.
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .await
+            .context("Opening apply marker")?;
+        file.write_all(&serde_json::to_vec(config)?)
+            .await
+            .context("Writing apply marker")?;
+        file.sync_all().await.context("Syncing apply marker")?;
+    }
+    Ok(())
+}
+
+/// Remove the apply marker after a successful apply.
+async fn marker_clear(options: &Options) -> Result<()> {
+    if let Some(path) = &options.apply_marker {
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).context("Removing apply marker");
             }
+            _ => {}
         }
     }
+    Ok(())
+}
 
-    let networks: Vec<_> = state.iter().map(|(_port, state)| state.clone()).collect();
+/// Called on startup, recovers from an interrupted apply.
+///
+/// If an apply marker exists, the previous apply did not complete and the
+/// host is in an unknown state somewhere between the old and the new config.
+/// Since applying is differential, applying the config recorded in the
+/// marker again brings the host to that config. If that fails too, the
+/// networks are left as they are and the error is logged, since tearing
+/// them down would cut off every peer until the manager pushes a config,
+/// which brings the host to a known state either way. The marker is
+/// removed afterwards, so that a failing config is not retried on every
+/// start.
+pub async fn recover(global: &Global) -> Result<()> {
+    let path = match &global.options().apply_marker {
+        Some(path) if path.exists() => path,
+        _ => return Ok(()),
+    };
 
-    apply_nginx(&networks, global.options())
+    warn!("Previous apply was interrupted, recovering");
+    let config: Result<GatewayConfig> = tokio::fs::read(path)
         .await
-        .context("Applying nginx configuration")?;
+        .context("Reading apply marker")
+        .and_then(|data| serde_json::from_slice(&data).context("Parsing apply marker"));
+    let result = match config {
+        Ok(config) => apply(global, &config).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        error!("Error reapplying interrupted config, keeping networks until the next config: {e:#}");
+        marker_clear(global.options()).await?;
+    }
 
     Ok(())
 }
 
+/// Turn a failed check of a config into [`GatewayError::Validation`], so that
+/// the manager can tell invalid configs apart from failures applying them.
+pub fn invalid(error: impl Into<anyhow::Error>) -> GatewayError {
+    GatewayError::Validation(format!("{:#}", error.into()))
+}
+
+/// Mask the private and preshared keys of the networks in an error, turning
+/// it into a single message with the whole chain of causes. The errors built
+/// here never contain keys, but external tools such as `wg` echo back lines of
+/// the config they fail to parse.
+fn redact_error<'a>(
+    error: anyhow::Error,
+    networks: impl Iterator<Item = &'a NetworkState>,
+) -> anyhow::Error {
+    let mut message = format!("{error:#}");
+    for network in networks {
+        message = message.replace(&network.private_key.to_string(), "[redacted]");
+        for peer in network.peers.values() {
+            if let Some(preshared_key) = &peer.preshared_key {
+                message = message.replace(&preshared_key.to_string(), "[redacted]");
+            }
+        }
+    }
+    anyhow!(message)
+}
+
+/// Parse a JSON config. If it fails to parse because of one of its networks,
+/// the error names the network and peer, rather than only carrying the
+/// error of the key library.
+pub fn parse_config<T: DeserializeOwned>(data: &str) -> Result<T> {
+    serde_json::from_str(data).map_err(|error| {
+        serde_json::from_str(data)
+            .ok()
+            .and_then(|value| config_parse_error(&value))
+            .unwrap_or_else(|| error.into())
+    })
+}
+
+/// Find the network of a JSON config, keyed by port, which fails to parse.
+/// Removed networks of partial configs are `null` and skipped. Keys are not
+/// included in the error, since they may be secret.
+pub fn config_parse_error(networks: &serde_json::Value) -> Option<anyhow::Error> {
+    for (port, network) in networks.as_object()? {
+        if network.is_null() {
+            continue;
+        }
+        if let Err(error) = serde_json::from_value::<NetworkState>(network.clone()) {
+            let error = network_parse_error(network).unwrap_or_else(|| error.to_string());
+            return Some(anyhow!("Network {port}: {error}"));
+        }
+    }
+    None
+}
+
+/// Find the key or peer of a JSON network which fails to parse.
+fn network_parse_error(network: &serde_json::Value) -> Option<String> {
+    if let Some(key) = network.get("private_key") {
+        if serde_json::from_value::<Privkey>(key.clone()).is_err() {
+            return Some("private key is not a valid WireGuard key".to_string());
+        }
+    }
+    for (pubkey, peer) in network.get("peers")?.as_object()? {
+        if Pubkey::from_str(pubkey).is_err() {
+            return Some(format!("public key {pubkey} is not a valid WireGuard key"));
+        }
+        if let Some(key) = peer.get("preshared_key").filter(|key| !key.is_null()) {
+            if serde_json::from_value::<Secret>(key.clone()).is_err() {
+                return Some(format!(
+                    "peer {pubkey}: preshared key is not a valid WireGuard key"
+                ));
+            }
+        }
+        if let Err(error) = serde_json::from_value::<PeerState>(peer.clone()) {
+            return Some(format!("peer {pubkey}: {error}"));
+        }
+    }
+    None
+}
+
+/// Normalize a network state so that the stored state matches what is
+/// applied, logging what was changed. Networks without a listen port listen
+/// on the port they are keyed by, and networks without an MTU get the
+/// gateway's default MTU.
+fn normalize_network(options: &Options, port: u16, network: &mut NetworkState) {
+    if network.listen_port == 0 {
+        network.listen_port = port;
+    }
+    network.mtu.get_or_insert(options.default_mtu);
+    for (peer, allowed_ip) in network.normalize() {
+        warn!(
+            "Network {port} peer {peer}: allowed IP {allowed_ip} has host bits set, using {}",
+            allowed_ip.trunc()
+        );
+    }
+}
+
+/// Delete the veth pair and network namespace of a network. The outer veth
+/// is deleted explicitly, which also detaches it from the bridge, since
+/// namespaces are torn down asynchronously and the kernel only removes the
+/// pair once that is done. Without this, a network reusing the port could
+/// fail to create its veth pair.
+pub async fn remove_network(global: &Global, netns: &str, veth: &str) -> Result<()> {
+    let paths = global.paths();
+    if util::link_exists(paths, None, veth).await? {
+        util::veth_del(paths, None, veth).await?;
+    }
+    util::netns_del(paths, netns).await?;
+    for _ in 0..TEARDOWN_RETRIES {
+        if !util::link_exists(paths, None, veth).await? {
+            return Ok(());
+        }
+        tokio::time::sleep(TEARDOWN_INTERVAL).await;
+    }
+    Err(anyhow!("Timed out waiting for {veth} to be removed"))
+}
+
 /// Make sure the bridge interface exists, is up and has a certain address
 /// set up.
-pub async fn apply_bridge(_name: &str, addr: &[IpNet]) -> Result<()> {
-    if !bridge_exists(None, BRIDGE_INTERFACE).await? {
-        bridge_add(None, BRIDGE_INTERFACE).await?;
+pub async fn apply_bridge(paths: &util::Paths, _name: &str, addr: &[IpNet]) -> Result<()> {
+    if !util::link_exists(paths, None, BRIDGE_INTERFACE).await? {
+        util::bridge_add(paths, None, BRIDGE_INTERFACE).await?;
     }
 
-    apply_addr(None, BRIDGE_INTERFACE, &addr)
+    // the bridge is shared with other gateways on this host, and may have
+    // addresses set up by the host, so addresses are only ever added to it.
+    apply_addr_add(paths, None, BRIDGE_INTERFACE, &addr)
         .await
         .context("Setting up bridge interface")?;
 
-    apply_interface_up(None, BRIDGE_INTERFACE)
+    apply_interface_up(paths, None, BRIDGE_INTERFACE)
         .await
         .context("Bringing bridge interface up")?;
 
@@ -173,130 +787,489 @@ pub async fn apply_bridge(_name: &str, addr: &[IpNet]) -> Result<()> {
 
 /// Apply a given network state.
 pub async fn apply_network(global: &Global, network: &NetworkState) -> Result<()> {
-    apply_netns(network).await?;
-    apply_wireguard(network).await?;
-    apply_veth(network).await?;
+    let paths = global.paths();
+    let netns = network.netns_name(&global.options().netns_prefix);
+
+    // the outer veth and the bridge address of a network are derived from
+    // its port alone, so an outer veth without our namespace belongs to
+    // another gateway on this host using the same port.
+    if !util::netns_exists(paths, &netns).await?
+        && util::link_exists(paths, None, &network.veth_name()).await?
+    {
+        return Err(anyhow!(
+            "Port {} is already used by another gateway on this host",
+            network.listen_port
+        ));
+    }
+
+    apply_netns(paths, &netns).await?;
+    apply_wireguard(global, network).await?;
+    apply_veth(
+        paths,
+        &netns,
+        network,
+        network.mtu.unwrap_or(global.options().default_mtu),
+    )
+    .await?;
+
+    apply_egress_route(paths, &netns, network)
+        .await
+        .context("Applying egress route")?;
 
     let _lock = global.iptables_lock().lock().await;
-    apply_forwarding(network).await?;
+    apply_forwarding(paths, &netns, network).await?;
+
     Ok(())
 }
 
-/// Given a network state, make sure the network namespace associated with it exists.
-pub async fn apply_netns(network: &NetworkState) -> Result<()> {
-    let netns = network.netns_name();
+/// Routing table of an egress policy tag, if it has one.
+fn egress_table(options: &Options, tag: &str) -> Option<u32> {
+    options
+        .egress_table
+        .iter()
+        .find(|(name, _)| name == tag)
+        .map(|(_, table)| *table)
+}
+
+/// Make sure that the egress policies of networks map to a routing table.
+fn check_egress_policies<'a>(
+    options: &Options,
+    networks: impl Iterator<Item = (&'a u16, &'a NetworkState)>,
+) -> Result<()> {
+    for (port, network) in networks {
+        if let Some(tag) = &network.egress_policy {
+            if egress_table(options, tag).is_none() {
+                return Err(anyhow!(
+                    "Network {port} has egress policy {tag}, which has no routing table"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
 
+/// Route traffic leaving a network with an egress policy out of its veth
+/// interface towards the bridge, where it is masqueraded to the veth address.
+/// Networks without one have no default route, so their traffic cannot
+/// leave.
+pub async fn apply_egress_route(
+    paths: &util::Paths,
+    netns: &str,
+    network: &NetworkState,
+) -> Result<()> {
+    match network.egress_policy {
+        Some(_) => {
+            util::route_default_replace(
+                paths,
+                Some(netns),
+                BRIDGE_NET.addr().into(),
+                &network.veth_name(),
+            )
+            .await
+        }
+        None => util::route_default_del(paths, Some(netns)).await,
+    }
+}
+
+/// Make sure that traffic from networks with an egress policy is routed by
+/// the routing table of their policy, with one rule per network matching its
+/// veth address. Rules of networks which were removed or lost their policy
+/// are removed, since removing the namespace does not remove them.
+pub async fn apply_egress_rules(global: &Global, networks: &[NetworkState]) -> Result<()> {
+    let options = global.options();
+    let target: BTreeSet<(Ipv4Addr, u32)> = networks
+        .iter()
+        .filter_map(|network| {
+            let table = egress_table(options, network.egress_policy.as_ref()?)?;
+            Some((network.veth_ipv4net().addr(), table))
+        })
+        .collect();
+    let current: BTreeSet<(Ipv4Addr, u32)> = util::rule_list(global.paths(), EGRESS_RULE_PRIORITY)
+        .await?
+        .into_iter()
+        .collect();
+    for (source, table) in current.difference(&target) {
+        util::rule_del(global.paths(), *source, *table, EGRESS_RULE_PRIORITY).await?;
+    }
+    for (source, table) in target.difference(&current) {
+        util::rule_add(global.paths(), *source, *table, EGRESS_RULE_PRIORITY).await?;
+    }
+    Ok(())
+}
+
+/// Make sure the network namespace of a network exists.
+pub async fn apply_netns(paths: &util::Paths, netns: &str) -> Result<()> {
     // make sure that netns exists
-    if !netns_exists(&netns).await? {
-        netns_add(&netns).await?;
+    if !util::netns_exists(paths, netns).await? {
+        util::netns_add(paths, netns).await?;
     }
 
     Ok(())
 }
 
-/// Apply the wireguard configuration associated with a network state.
-pub async fn apply_wireguard(network: &NetworkState) -> Result<()> {
-    let netns = network.netns_name();
+/// Apply the wireguard configuration associated with a network state. Peers
+/// which are removed for exceeding their quota are left out.
+pub async fn apply_wireguard(global: &Global, network: &NetworkState) -> Result<()> {
+    let netns = network.netns_name(&global.options().netns_prefix);
     let wgif = network.wgif_name();
 
     // make sure that the wireguard interface works
-    if !wireguard_exists(&netns, &wgif).await? {
+    if !util::wireguard_exists(global.paths(), &netns, &wgif).await? {
         info!("Wireguard network does not exist");
         // create wireguard config in netns
-        wireguard_create(Some(&netns), &wgif).await?;
+        util::wireguard_create(global.paths(), &netns, &wgif, global.wireguard().mode).await?;
     }
 
-    let show = interface_show(Some(&netns), &wgif).await?;
-    let mtu = show
-        .mtu
-        .ok_or(anyhow!("Missing MTU for WireGuard network"))?;
-    if mtu != network.mtu {
-        interface_mtu(Some(&netns), &wgif, network.mtu).await?;
+    let mtu = network.mtu.unwrap_or(global.options().default_mtu);
+    if let Err(error) = apply_mtu(global.paths(), Some(&netns), &wgif, mtu).await {
+        mtu_rejected(global, network, &netns, &wgif, mtu, error)
+            .await
+            .context("Setting wireguard interface MTU")?;
     }
 
-    apply_interface_up(Some(&netns), &wgif)
+    apply_interface_up(global.paths(), Some(&netns), &wgif)
         .await
         .context("Setting wireguard interface UP")?;
 
-    // write wireguard config
-    netns_write_file(
-        &netns,
-        Path::new(&format!("wireguard/{}.conf", &wgif)),
-        &network.to_config(),
-    )
-    .await?;
+    // write wireguard config, without peers that exceeded their quota
+    let blocked = global.quota_blocked(network.listen_port);
+    write_wireguard_config(global.options(), &netns, &wgif, network, &blocked)
+        .await
+        .context("Writing wireguard config")?;
 
     // set wireguard interface addresses to allow kernel ingress traffic
-    apply_addr(Some(&netns), &wgif, &network.address)
+    apply_addr(global.paths(), Some(&netns), &wgif, &network.address)
         .await
         .context("Applying wireguard interface addresses")?;
 
     // sync config of wireguard netns
-    wireguard_syncconf(&netns, &wgif).await?;
+    syncconf(global, &netns, &wgif).await?;
+
+    if global.options().verify_apply {
+        verify_wireguard(global.paths(), &netns, &wgif, network, &blocked)
+            .await
+            .context("Verifying wireguard config")?;
+    }
+
+    Ok(())
+}
+
+/// Sync the config of a wireguard interface, retrying with backoff if it
+/// fails for a reason other than `wg` rejecting the config, such as the
+/// interface not being ready yet.
+async fn syncconf(global: &Global, netns: &str, wgif: &str) -> Result<()> {
+    let options = global.options();
+    let mut backoff = SYNCCONF_BACKOFF;
+    let mut attempt = 0;
+    let config = wireguard_config_path(options, netns, wgif);
+    loop {
+        match util::wireguard_syncconf(global.paths(), netns, wgif, &config).await {
+            Ok(()) => return Ok(()),
+            Err(e)
+                if attempt < options.syncconf_retries && !util::wireguard_config_rejected(&e) =>
+            {
+                attempt += 1;
+                warn!("Syncing {wgif} in {netns} failed, retrying in {backoff:?} ({attempt}/{}): {e:#}", options.syncconf_retries);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Write the WireGuard config of a network into its namespace, leaving out
+/// the blocked peers. The config is written peer by peer through a buffered
+/// writer rather than rendered into one string first, so that networks with
+/// thousands of peers do not need their whole config in memory. It is written
+/// to a temporary file first and then moved into place, so that `wg` never
+/// reads a partially written config.
+pub async fn write_wireguard_config(
+    options: &Options,
+    netns: &str,
+    wgif: &str,
+    network: &NetworkState,
+    blocked: &BTreeSet<Pubkey>,
+) -> Result<()> {
+    let dir = wireguard_config_dir(options, netns);
+    tokio::fs::create_dir_all(&dir).await?;
+    let path = dir.join(format!("{wgif}.conf"));
+    let partial = dir.join(format!("{wgif}.conf.partial"));
+    let file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&partial)
+        .await?;
+    let mut writer = tokio::io::BufWriter::new(file);
+
+    writer.write_all(network.config_header().as_bytes()).await?;
+    for (pubkey, peer) in &network.peers {
+        if blocked.contains(pubkey) {
+            continue;
+        }
+        let peer = format!("\n{}\n", network.peer_config(pubkey, peer));
+        writer.write_all(peer.as_bytes()).await?;
+    }
+    writer.flush().await?;
+    writer.get_ref().sync_all().await?;
+    tokio::fs::rename(&partial, &path).await?;
+    Ok(())
+}
+
+/// Directory on the host that the WireGuard configs of a network namespace
+/// are written to.
+fn wireguard_config_dir(options: &Options, netns: &str) -> PathBuf {
+    match &options.wireguard_config_dir {
+        Some(dir) => dir.join(netns),
+        None => util::netns_etc_path(netns).join("wireguard"),
+    }
+}
+
+/// Path of the WireGuard config of an interface as seen by `wg` inside of
+/// its network namespace, where the namespace's directory in `/etc/netns` is
+/// mounted as `/etc`.
+fn wireguard_config_path(options: &Options, netns: &str, wgif: &str) -> PathBuf {
+    let dir = match &options.wireguard_config_dir {
+        Some(dir) => dir.join(netns),
+        None => PathBuf::from("/etc/wireguard"),
+    };
+    dir.join(format!("{wgif}.conf"))
+}
+
+/// Read back the peers of a wireguard interface and make sure that they match
+/// the network state, failing with the peers which are missing, unexpected or
+/// have different allowed IPs. Catches syncconf only partially applying a
+/// config.
+pub async fn verify_wireguard(
+    paths: &util::Paths,
+    netns: &str,
+    wgif: &str,
+    network: &NetworkState,
+    blocked: &BTreeSet<Pubkey>,
+) -> Result<()> {
+    let stats = util::wireguard_dump(paths, netns, wgif)
+        .await
+        .context("Fetching wireguard stats")?;
+    let live: BTreeMap<Pubkey, BTreeSet<IpNet>> = stats
+        .peers()
+        .iter()
+        .map(|peer| (peer.public_key, peer.allowed_ips.iter().cloned().collect()))
+        .collect();
+    let desired: BTreeMap<Pubkey, BTreeSet<IpNet>> = network
+        .peers
+        .iter()
+        .filter(|(pubkey, _)| !blocked.contains(pubkey))
+        .map(|(pubkey, peer)| (*pubkey, peer.allowed_ips.iter().cloned().collect()))
+        .collect();
+
+    let mut problems = vec![];
+    for (pubkey, allowed_ips) in &desired {
+        match live.get(pubkey) {
+            None => problems.push(format!("peer {pubkey} is missing")),
+            Some(live) if live != allowed_ips => {
+                problems.push(format!("peer {pubkey} has different allowed IPs"))
+            }
+            _ => {}
+        }
+    }
+    for pubkey in live.keys() {
+        if !desired.contains_key(pubkey) {
+            problems.push(format!("peer {pubkey} is unexpected"));
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(anyhow!(
+            "Interface {wgif} differs from config: {}",
+            problems.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Handle an MTU which could not be set on an interface of a network. A
+/// wrong MTU degrades the network, but does not break it, so unless MTU
+/// failures are configured to be fatal, the network keeps the interface's
+/// current MTU and an event is emitted.
+async fn mtu_rejected(
+    global: &Global,
+    network: &NetworkState,
+    netns: &str,
+    interface: &str,
+    mtu: usize,
+    error: anyhow::Error,
+) -> Result<()> {
+    if global.options().strict_mtu {
+        return Err(error);
+    }
+    let current = util::interface_show(global.paths(), Some(netns), interface)
+        .await
+        .ok()
+        .and_then(|show| show.mtu);
+    warn!(
+        "Unable to set MTU of {interface} to {mtu}, keeping MTU {}: {error:#}",
+        current
+            .map(|current| current.to_string())
+            .unwrap_or("unknown".to_string())
+    );
+
+    // nobody is subscribed to events while the manager is disconnected
+    let _ = global
+        .event(&GatewayEvent::MtuRejected(GatewayMtuRejectedEvent {
+            network: network.private_key.pubkey(),
+            listen_port: network.listen_port,
+            interface: interface.to_string(),
+            mtu,
+            current,
+        }))
+        .await;
+    Ok(())
+}
+
+/// Make sure that an interface has the given MTU.
+pub async fn apply_mtu(
+    paths: &util::Paths,
+    netns: Option<&str>,
+    interface: &str,
+    mtu: usize,
+) -> Result<()> {
+    let show = util::interface_show(paths, netns, interface).await?;
+    let current = show
+        .mtu
+        .ok_or(anyhow!("Missing MTU for interface {interface}"))?;
+    if current != mtu {
+        info!("Changing MTU of {interface} from {current} to {mtu}");
+        util::interface_mtu(paths, netns, interface, mtu).await?;
+    }
+    Ok(())
+}
+
+/// Given an interface and a network namespace, apply the addresses and
+/// remove any others.
+pub async fn apply_addr(
+    paths: &util::Paths,
+    netns: Option<&str>,
+    interface: &str,
+    target: &[IpNet],
+) -> Result<()> {
+    let current = apply_addr_add(paths, netns, interface, target).await?;
 
+    // remove stale addresses, leaving the link-local addresses that the
+    // kernel assigns to IPv6-enabled interfaces.
+    for addr in &current {
+        let link_local = match addr {
+            IpNet::V6(ipv6) => ipv6.addr().segments()[0] & 0xffc0 == 0xfe80,
+            IpNet::V4(_) => false,
+        };
+        if !link_local && !target.contains(addr) {
+            util::addr_del(paths, netns, interface, *addr).await?;
+        }
+    }
     Ok(())
 }
 
-/// Given an interface and a network namespace, apply the address.
-pub async fn apply_addr(netns: Option<&str>, interface: &str, target: &[IpNet]) -> Result<()> {
-    // FIXME: this will not remove addresses.
-    let current = addr_list(netns, interface).await?;
+/// Given an interface and a network namespace, add the addresses which are
+/// missing, leaving any others alone. Returns the addresses the interface had
+/// before.
+pub async fn apply_addr_add(
+    paths: &util::Paths,
+    netns: Option<&str>,
+    interface: &str,
+    target: &[IpNet],
+) -> Result<Vec<IpNet>> {
+    let current = util::addr_list(paths, netns, interface).await?;
     for addr in target {
         if !current.contains(addr) {
-            addr_add(netns, interface, *addr).await?;
+            util::addr_add(paths, netns, interface, *addr).await?;
         }
     }
-    Ok(())
+    Ok(current)
 }
 
 /// Make sure that an interface in a given network namespace (or in the root
 /// namespace if none is supplied) is not DOWN.
-pub async fn apply_interface_up(netns: Option<&str>, interface: &str) -> Result<()> {
-    let status = interface_show(netns, interface).await?;
+pub async fn apply_interface_up(
+    paths: &util::Paths,
+    netns: Option<&str>,
+    interface: &str,
+) -> Result<()> {
+    let status = util::interface_show(paths, netns, interface).await?;
     if status.is_down() {
-        interface_up(netns, interface).await?;
+        util::interface_up(paths, netns, interface).await?;
     }
     Ok(())
 }
 
-/// Given a network state, apply the veth configuration by creating the veth pair.
-pub async fn apply_veth(network: &NetworkState) -> Result<()> {
-    let netns = network.netns_name();
+/// Addresses of the bridge interface. The IPv6 bridge network is only set up
+/// while a network forwards to IPv6 targets, so that hosts without IPv6 are
+/// not affected.
+fn bridge_addresses<'a>(networks: impl IntoIterator<Item = &'a NetworkState>) -> Vec<IpNet> {
+    let mut addresses = vec![(*BRIDGE_NET).into()];
+    if networks
+        .into_iter()
+        .any(|network| network.has_ipv6_forwarding())
+    {
+        addresses.push((*BRIDGE_NET6).into());
+    }
+    addresses
+}
 
+/// Given a network state, apply the veth configuration by creating the veth pair.
+pub async fn apply_veth(
+    paths: &util::Paths,
+    netns: &str,
+    network: &NetworkState,
+    mtu: usize,
+) -> Result<()> {
     // create veth pair
     let veth_name = network.veth_name();
-    if !veth_exists(&netns, &veth_name).await? {
-        veth_add(&netns, &veth_name, &veth_name).await?;
+    if !util::link_exists(paths, Some(netns), &veth_name).await? {
+        util::veth_add(paths, netns, &veth_name, &veth_name).await?;
     }
 
     // make sure veth interfaces have addresses set
-    let addr: Ipv4Net = network.veth_ipv4net().into();
-    let addr: IpNet = addr.into();
-    let addr = vec![addr];
-    apply_addr(Some(&netns), &veth_name, &addr)
+    let addr = network.veth_addresses();
+    apply_addr(paths, Some(netns), &veth_name, &addr)
         .await
         .context("Applying veth addr")?;
     //apply_addr(None, &veth_name, &addr).await
     //    .context("Applying veth addr")?;
-    apply_link_master(None, &veth_name, BRIDGE_INTERFACE)
+
+    // forwarded traffic crosses the veth pair into the wireguard interface,
+    // so both halves need the same MTU as the wireguard network to avoid
+    // fragmentation or drops.
+    apply_mtu(paths, Some(netns), &veth_name, mtu)
+        .await
+        .context("Setting inner veth MTU")?;
+    apply_mtu(paths, None, &veth_name, mtu)
+        .await
+        .context("Setting outer veth MTU")?;
+    apply_link_master(paths, None, &veth_name, BRIDGE_INTERFACE)
         .await
         .context("Setting veth master")?;
 
     // make sure inner veth is up
-    apply_interface_up(Some(&netns), &veth_name)
+    apply_interface_up(paths, Some(netns), &veth_name)
         .await
         .context("Making inner veth interface UP")?;
-    apply_interface_up(None, &veth_name)
+    apply_interface_up(paths, None, &veth_name)
         .await
         .context("Marking outer veth interface UP")?;
 
     Ok(())
 }
 
-pub async fn apply_link_master(netns: Option<&str>, interface: &str, master: &str) -> Result<()> {
-    let current = link_get_master(netns, interface).await?;
+pub async fn apply_link_master(
+    paths: &util::Paths,
+    netns: Option<&str>,
+    interface: &str,
+    master: &str,
+) -> Result<()> {
+    let current = util::link_get_master(paths, netns, interface).await?;
     if current.is_none() || current.as_deref() != Some(master) {
-        link_set_master(netns, interface, master)
+        util::link_set_master(paths, netns, interface, master)
             .await
             .context("Setting master of interface")?;
     }
@@ -319,27 +1292,109 @@ fn clean_iptables(input: &str) -> String {
 }
 
 /// Apply the forwarding configuration by writing out an iptables state and restoring it.
-pub async fn apply_forwarding(network: &NetworkState) -> Result<()> {
-    let netns = network.netns_name();
-    let config = network.port_config();
+/// IPv6 rules are only managed for networks which have an IPv6 address.
+pub async fn apply_forwarding(
+    paths: &util::Paths,
+    netns: &str,
+    network: &NetworkState,
+) -> Result<()> {
+    let config = network.port_config()?;
+    let config_v6 = network.port_config_v6()?;
     let context = tera::Context::from_serialize(&config)?;
     let savefile = TERA_TEMPLATES.render("iptables.save", &context)?;
     let savefile = clean_iptables(&savefile);
-    let current = iptables_save(Some(&netns)).await?;
+    let current = util::iptables_save(paths, Some(netns)).await?;
     let current = clean_iptables(&current);
 
     if savefile != current {
-        iptables_restore(Some(&netns), &savefile).await?;
+        util::iptables_restore(paths, Some(netns), &savefile).await?;
+    }
+
+    if network.has_ipv6() {
+        let context = tera::Context::from_serialize(&config_v6)?;
+        let savefile = TERA_TEMPLATES.render("ip6tables.save", &context)?;
+        let savefile = clean_iptables(&savefile);
+        let current = util::ip6tables_save(paths, Some(netns)).await?;
+        let current = clean_iptables(&current);
+
+        if savefile != current {
+            util::ip6tables_restore(paths, Some(netns), &savefile).await?;
+        }
     }
 
     Ok(())
 }
 
 /// Apply an nginx configuration by writing out config files and restarting nginx.
-pub async fn apply_nginx(networks: &[NetworkState], options: &Options) -> Result<()> {
+pub async fn apply_nginx(global: &Global, networks: &[NetworkState]) -> Result<()> {
+    let options = global.options();
+
+    // without NGINX there is nothing to do, unless something is forwarded.
+    if !global.nginx() {
+        let forwarding = !options.custom_forwarding.is_empty()
+            || networks.iter().any(|network| !network.proxy.is_empty());
+        if forwarding {
+            return Err(anyhow!(
+                "NGINX is required for forwarding, but it is not available"
+            ));
+        }
+        return Ok(());
+    }
+
+    let forwarding = forwarding(options, networks)?;
+
+    // fill NGINX templates
+    let context = tera::Context::from_serialize(&forwarding)?;
+    let configs = [
+        (
+            options.nginx_module_path.as_path(),
+            TERA_TEMPLATES.render("nginx.conf", &context)?,
+        ),
+        (
+            options.nginx_site_path.as_path(),
+            TERA_TEMPLATES.render("sites.nginx.conf", &context)?,
+        ),
+    ];
+
+    // keep the previous configs, to restore them if the new ones are invalid
+    let mut previous = Vec::new();
+    for (path, _) in &configs {
+        previous.push((*path, nginx_config_read(path).await?));
+    }
+
+    for (path, config) in &configs {
+        tokio::fs::write(path, config.as_bytes())
+            .await
+            .with_context(|| format!("Writing {}", path.display()))?;
+    }
+
+    // only reload NGINX if it accepts the new configs
+    if let Err(error) = util::nginx_test(global.paths()).await {
+        for (path, config) in previous {
+            if let Err(e) = nginx_config_restore(path, config).await {
+                error!("Error restoring NGINX config {}: {e:#}", path.display());
+            }
+        }
+        return Err(error);
+    }
+
+    util::nginx_reload(global.paths()).await?;
+
+    Ok(())
+}
+
+/// Collect the forwarding of the networks and the command-line options,
+/// failing if a service cannot be forwarded. This is checked before a config
+/// is applied, so that invalid forwarding is rejected rather than left out.
+fn forwarding<'a>(
+    options: &Options,
+    networks: impl IntoIterator<Item = &'a NetworkState>,
+) -> Result<Forwarding> {
     let mut forwarding = Forwarding::new();
     for network in networks {
-        forwarding.add(network);
+        forwarding
+            .add(network)
+            .with_context(|| format!("Forwarding of network {}", network.listen_port))?;
     }
 
     // add custom forwarding from command-line options
@@ -347,15 +1402,237 @@ pub async fn apply_nginx(networks: &[NetworkState], options: &Options) -> Result
         forwarding.add_custom(url, *socket);
     }
 
-    // fill NGINX template
-    let context = tera::Context::from_serialize(&forwarding)?;
-    let config = TERA_TEMPLATES.render("nginx.conf", &context)?;
-    tokio::fs::write(Path::new(NGINX_MODULE_PATH), config.as_bytes()).await?;
-
-    let config = TERA_TEMPLATES.render("sites.nginx.conf", &context)?;
-    tokio::fs::write(Path::new(NGINX_SITE_PATH), config.as_bytes()).await?;
+    forwarding.set_listen(&options.proxy_listen);
+    Ok(forwarding)
+}
 
-    nginx_reload().await?;
+/// Read an NGINX config file, if it exists.
+async fn nginx_config_read(path: &Path) -> Result<Option<Vec<u8>>> {
+    match tokio::fs::read(path).await {
+        Ok(config) => Ok(Some(config)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Reading {}", path.display())),
+    }
+}
 
+/// Restore an NGINX config file to its previous contents, removing it if it
+/// did not exist before.
+async fn nginx_config_restore(path: &Path, config: Option<Vec<u8>>) -> Result<()> {
+    match config {
+        Some(config) => tokio::fs::write(path, config).await?,
+        None => tokio::fs::remove_file(path).await?,
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{fake_executable, global, options, LOG_ARGS};
+    use serde_json::json;
+
+    /// Create a directory with an `nginx` script which logs its arguments.
+    /// Unless `valid`, `nginx -t` fails.
+    fn fake_nginx(name: &str, valid: bool) -> PathBuf {
+        let test = if valid {
+            ""
+        } else {
+            "\n[ \"$1\" = -t ] && echo 'invalid config' >&2 && exit 1"
+        };
+        fake_executable(name, "nginx", &format!("{LOG_ARGS}{test}\nexit 0"))
+    }
+
+    fn network_json(private_key: &str, peers: serde_json::Value) -> serde_json::Value {
+        json!({
+            "private_key": private_key,
+            "address": ["10.0.0.1/24"],
+            "peers": peers,
+            "proxy": {},
+        })
+    }
+
+    fn parse_error(config: serde_json::Value) -> String {
+        parse_config::<GatewayConfig>(&config.to_string())
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn parse_config_valid() {
+        let private_key = Privkey::generate();
+        let config = json!({ "51820": network_json(&private_key.to_string(), json!({})) });
+        let config: GatewayConfig = parse_config(&config.to_string()).unwrap();
+        assert_eq!(config[&51820].private_key, private_key);
+    }
+
+    #[test]
+    fn parse_config_invalid_private_key() {
+        let config = json!({ "51820": network_json("secret-key", json!({})) });
+        let error = parse_error(config);
+        assert_eq!(
+            error,
+            "Network 51820: private key is not a valid WireGuard key"
+        );
+        assert!(!error.contains("secret-key"));
+    }
+
+    #[test]
+    fn parse_config_invalid_peer_key() {
+        let private_key = Privkey::generate().to_string();
+        let peers = json!({ "not-a-key": { "allowed_ips": [] } });
+        let config = json!({ "51820": network_json(&private_key, peers) });
+        assert_eq!(
+            parse_error(config),
+            "Network 51820: public key not-a-key is not a valid WireGuard key"
+        );
+    }
+
+    #[test]
+    fn parse_config_invalid_preshared_key() {
+        let private_key = Privkey::generate().to_string();
+        let peer = Privkey::generate().pubkey();
+        let peers =
+            json!({ peer.to_string(): { "allowed_ips": [], "preshared_key": "secret-psk" } });
+        let config = json!({ "51820": network_json(&private_key, peers) });
+        let error = parse_error(config);
+        assert_eq!(
+            error,
+            format!("Network 51820: peer {peer}: preshared key is not a valid WireGuard key")
+        );
+        assert!(!error.contains("secret-psk"));
+    }
+
+    #[test]
+    fn parse_config_partial_skips_removed() {
+        let config = json!({ "51820": null, "51821": network_json("secret-key", json!({})) });
+        let error = parse_config::<GatewayConfigPartial>(&config.to_string())
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Network 51821: private key is not a valid WireGuard key"
+        );
+    }
+
+    #[tokio::test]
+    async fn apply_overlapping_networks_invalid() {
+        let global = global(&options(), false);
+        let config = json!({
+            "51820": network_json(&Privkey::generate().to_string(), json!({})),
+            "51821": network_json(&Privkey::generate().to_string(), json!({})),
+        });
+        let config: GatewayConfig = parse_config(&config.to_string()).unwrap();
+        let error = apply(&global, &config).await.unwrap_err().to_string();
+        assert!(error.contains("overlapping subnets"));
+        assert!(matches!(
+            GatewayError::from_apply(&error),
+            Some(GatewayError::Validation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn apply_nginx_invalid_rolls_back() {
+        let dir = fake_nginx("nginx-invalid", false);
+        let mut options = options();
+        options.nginx_path = dir.join("nginx");
+        options.nginx_module_path = dir.join("module.conf");
+        options.nginx_site_path = dir.join("site.conf");
+        std::fs::write(&options.nginx_module_path, "previous").unwrap();
+        let global = global(&options, true);
+
+        let result = apply_nginx(&global, &[]).await;
+        let module = std::fs::read_to_string(&options.nginx_module_path);
+        let site = options.nginx_site_path.exists();
+        let args = std::fs::read_to_string(dir.join("args"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(format!("{:#}", result.unwrap_err()).contains("invalid config"));
+        assert_eq!(module.unwrap(), "previous");
+        assert!(!site);
+        assert_eq!(args.unwrap().trim(), "-t");
+    }
+
+    #[tokio::test]
+    async fn apply_configured_paths() {
+        let dir = fake_nginx("paths", true);
+        let mut options = options();
+        options.nginx_path = dir.join("nginx");
+        options.nginx_module_path = dir.join("module.conf");
+        options.nginx_site_path = dir.join("site.conf");
+        options.wireguard_config_dir = Some(dir.join("wireguard"));
+        let global = global(&options, true);
+
+        let config = json!({ "51820": network_json(&Privkey::generate().to_string(), json!({})) });
+        let config: GatewayConfig = parse_config(&config.to_string()).unwrap();
+        let network = &config[&51820];
+        let nginx = apply_nginx(&global, &[network.clone()]).await;
+        let wireguard = write_wireguard_config(
+            &options,
+            "network-51820",
+            "wg51820",
+            network,
+            &BTreeSet::new(),
+        )
+        .await;
+        let module = options.nginx_module_path.is_file();
+        let site = options.nginx_site_path.is_file();
+        let wireguard_config =
+            std::fs::read_to_string(dir.join("wireguard/network-51820/wg51820.conf"));
+        let args = std::fs::read_to_string(dir.join("args"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        nginx.unwrap();
+        wireguard.unwrap();
+        assert!(module);
+        assert!(site);
+        assert!(wireguard_config.unwrap().contains("[Interface]"));
+        assert_eq!(args.unwrap(), "-t\n-s reload\n");
+    }
+
+    /// Create a directory with an `ip` script which logs its arguments and
+    /// reports a host without any networks.
+    fn fake_ip(name: &str) -> PathBuf {
+        let body = format!(
+            "{LOG_ARGS}\ncase \"$*\" in\n\"-j link show\"*) echo '[{{\"operstate\": \"UP\"}}]' ;;\n-j*) echo '[]' ;;\nesac"
+        );
+        fake_executable(name, "ip", &body)
+    }
+
+    #[tokio::test]
+    async fn recover_reapplies_marker() {
+        let dir = fake_ip("recover");
+        let mut options = options();
+        options.ip_path = dir.join("ip");
+        options.apply_marker = Some(dir.join("marker"));
+        std::fs::write(dir.join("marker"), "{}").unwrap();
+        let global = global(&options, false);
+
+        let result = recover(&global).await;
+        let marker = dir.join("marker").exists();
+        let args = std::fs::read_to_string(dir.join("args"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        assert!(!marker);
+        assert!(args.unwrap().lines().any(|line| line == "-j netns list"));
+    }
+
+    #[tokio::test]
+    async fn recover_invalid_marker_cleared() {
+        let dir = fake_ip("recover-invalid");
+        let mut options = options();
+        options.ip_path = dir.join("ip");
+        options.apply_marker = Some(dir.join("marker"));
+        std::fs::write(dir.join("marker"), "not a config").unwrap();
+        let global = global(&options, false);
+
+        let result = recover(&global).await;
+        let marker = dir.join("marker").exists();
+        let args = dir.join("args").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        assert!(!marker);
+        assert!(!args);
+    }
+}
@@ -1,28 +1,39 @@
-use crate::gateway::BRIDGE_NET;
+use crate::gateway::{BRIDGE_NET, BRIDGE_NET6};
 use anyhow::{anyhow, Context};
-use fractal_gateway_client::{NetworkState, PeerState};
-use ipnet::{IpAdd, IpNet, Ipv4Net};
+use fractal_gateway_client::{BufferedTraffic, NetworkState, PeerState, ProxyOptions, TrafficInfo};
+use ipnet::{IpAdd, IpNet, Ipv4Net, Ipv6Net};
 use itertools::Itertools;
 use log::*;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
-use std::net::{IpAddr, SocketAddr};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use url::Url;
 use wireguard_keys::{Privkey, Pubkey, Secret};
 
-pub const NETNS_PREFIX: &'static str = "network-";
 pub const VETH_PREFIX: &'static str = "veth";
 pub const WIREGUARD_PREFIX: &'static str = "wg";
 const PORT_MAPPING_START: u16 = 2000;
 
+/// Ports NGINX listens on for HTTP and HTTPS forwarding.
+const HTTP_PORT: u16 = 80;
+const HTTPS_PORT: u16 = 443;
+
+/// Persistent keepalive interval used for peers when neither the peer nor
+/// its network set one.
+pub const DEFAULT_PERSISTENT_KEEPALIVE: u16 = 25;
+
 #[derive(Serialize, Clone, Debug)]
 pub struct PortConfig {
     interface_in: String,
     interface_out: String,
-    ip_source: IpAddr,
+    ip_source: Option<IpAddr>,
     mappings: Vec<PortMapping>,
+    /// Whether traffic leaving the network through the veth interface is
+    /// masqueraded, for networks with an egress policy
+    egress: bool,
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -32,32 +43,182 @@ pub struct PortMapping {
     ip_out: IpAddr,
 }
 
+/// How the watchdog decides whether a peer is connected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectStrategy {
+    /// Peer is connected while it has had a recent handshake.
+    Handshake,
+    /// Peer is connected while it has had a recent handshake and generates
+    /// more traffic than its keepalives alone would.
+    Traffic,
+}
+
+impl FromStr for DisconnectStrategy {
+    type Err = anyhow::Error;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "handshake" => Ok(DisconnectStrategy::Handshake),
+            "traffic" => Ok(DisconnectStrategy::Traffic),
+            other => Err(anyhow!("Unknown disconnect strategy: {other}")),
+        }
+    }
+}
+
+/// Token used to authenticate with the manager. Its debug output only shows
+/// its length, so that the options can be logged.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Token(pub String);
+
+impl FromStr for Token {
+    type Err = std::convert::Infallible;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Ok(Token(input.to_string()))
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted, {} bytes>", self.0.len())
+    }
+}
+
+/// URL of the manager. Its debug output replaces the password, if it has
+/// one, so that the options can be logged.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ManagerUrl(pub Url);
+
+impl FromStr for ManagerUrl {
+    type Err = url::ParseError;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Url::parse(input).map(ManagerUrl)
+    }
+}
+
+impl fmt::Debug for ManagerUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut url = self.0.clone();
+        if url.password().is_some() {
+            let _ = url.set_password(Some("redacted"));
+        }
+        fmt::Debug::fmt(&url, f)
+    }
+}
+
 pub trait NetworkStateExt {
     fn to_config(&self) -> String;
-    fn netns_name(&self) -> String;
+    fn config_header(&self) -> String;
+    fn peer_keepalive(&self, peer: &PeerState) -> u16;
+    fn peer_config(&self, pubkey: &Pubkey, peer: &PeerState) -> String;
+    fn netns_name(&self, prefix: &str) -> String;
     fn wgif_name(&self) -> String;
     fn veth_name(&self) -> String;
     fn veth_ipv4net(&self) -> Ipv4Net;
+    fn veth_ipv6net(&self) -> Ipv6Net;
+    fn veth_addresses(&self) -> Vec<IpNet>;
+    fn veth_socket(&self, port: u16, target: &SocketAddr) -> SocketAddr;
     fn port_mappings(&self) -> Vec<(Url, u16, SocketAddr)>;
-    fn port_config(&self) -> PortConfig;
+    fn port_config(&self) -> anyhow::Result<PortConfig>;
+    fn port_config_v6(&self) -> anyhow::Result<PortConfig>;
+    fn has_ipv6(&self) -> bool;
+    fn has_ipv6_forwarding(&self) -> bool;
+}
+
+/// Address of the veth interface of the network with the given listen port,
+/// offset from the start of the bridge network by the port.
+pub fn veth_address(port: u16) -> Ipv4Addr {
+    BRIDGE_NET.network().saturating_add(port as u32)
+}
+
+/// IPv6 address of the veth interface of the network with the given listen
+/// port, offset from the start of the IPv6 bridge network by the port.
+pub fn veth_address_v6(port: u16) -> Ipv6Addr {
+    BRIDGE_NET6.network().saturating_add(port as u128)
+}
+
+/// Listen port of the network a namespace belongs to, parsed from a
+/// namespace name made by [`NetworkStateExt::netns_name`]. Names without the
+/// prefix, or with anything but a port after it, belong to something else.
+pub fn parse_netns_port(prefix: &str, name: &str) -> Option<u16> {
+    let suffix = name.strip_prefix(prefix)?;
+    let port: u16 = suffix.parse().ok()?;
+    (port.to_string() == suffix).then(|| port)
+}
+
+/// Build the port forwarding config of a network for one address family,
+/// only containing mappings of that family. Fails if the network has
+/// mappings of that family but no address of it to use as their source.
+fn port_config_family(network: &NetworkState, ipv6: bool) -> anyhow::Result<PortConfig> {
+    let family = if ipv6 { "IPv6" } else { "IPv4" };
+    let ip_source = network
+        .address
+        .iter()
+        .map(|address| address.addr())
+        .find(|address| address.is_ipv6() == ipv6);
+    let mappings: Vec<PortMapping> = network
+        .port_mappings()
+        .iter()
+        .filter(|(_, _, sock)| sock.is_ipv6() == ipv6)
+        .map(|(_, port, sock)| PortMapping {
+            port_in: *port,
+            port_out: sock.port(),
+            ip_out: sock.ip(),
+        })
+        .collect();
+    if ip_source.is_none() && !mappings.is_empty() {
+        return Err(anyhow!(
+            "Network {} has {family} forwarding but no {family} address",
+            network.listen_port
+        ));
+    }
+    Ok(PortConfig {
+        interface_in: network.veth_name(),
+        interface_out: network.wgif_name(),
+        ip_source,
+        mappings,
+        // egress traffic is only routed over IPv4
+        egress: network.egress_policy.is_some() && !ipv6,
+    })
 }
 
 impl NetworkStateExt for NetworkState {
     fn to_config(&self) -> String {
+        let mut config = self.config_header();
+        use std::fmt::Write;
+        for (pubkey, peer) in &self.peers {
+            writeln!(config, "\n{}", self.peer_config(pubkey, peer)).unwrap();
+        }
+        config
+    }
+
+    /// Interface section of the WireGuard config, without any peers.
+    fn config_header(&self) -> String {
         let mut config = String::new();
         use std::fmt::Write;
         writeln!(config, "[Interface]").unwrap();
         writeln!(config, "ListenPort = {}", self.listen_port).unwrap();
         writeln!(config, "PrivateKey = {}", self.private_key.to_string()).unwrap();
-
-        for (pubkey, peer) in &self.peers {
-            writeln!(config, "\n{}", peer.to_config(pubkey)).unwrap();
-        }
         config
     }
 
-    fn netns_name(&self) -> String {
-        format!("{}{}", NETNS_PREFIX, self.listen_port)
+    /// Effective persistent keepalive interval of a peer of this network.
+    fn peer_keepalive(&self, peer: &PeerState) -> u16 {
+        peer.persistent_keepalive
+            .or(self.default_keepalive)
+            .unwrap_or(DEFAULT_PERSISTENT_KEEPALIVE)
+    }
+
+    /// Peer section of the WireGuard config for a peer of this network,
+    /// with its effective persistent keepalive.
+    fn peer_config(&self, pubkey: &Pubkey, peer: &PeerState) -> String {
+        peer.to_config(pubkey, self.peer_keepalive(peer))
+    }
+
+    /// Name of the network namespace of this network. The prefix is
+    /// configurable so that several gateways can share a host, the interfaces
+    /// inside of the namespace do not need it. The outer veth interface is
+    /// named by port only, since listen ports have to be unique per host anyway.
+    fn netns_name(&self, prefix: &str) -> String {
+        format!("{}{}", prefix, self.listen_port)
     }
 
     fn wgif_name(&self) -> String {
@@ -69,9 +230,31 @@ impl NetworkStateExt for NetworkState {
     }
 
     fn veth_ipv4net(&self) -> Ipv4Net {
-        let addr = BRIDGE_NET.network();
-        let addr = addr.saturating_add(self.listen_port as u32);
-        Ipv4Net::new(addr, BRIDGE_NET.prefix_len()).unwrap()
+        Ipv4Net::new(veth_address(self.listen_port), BRIDGE_NET.prefix_len()).unwrap()
+    }
+
+    fn veth_ipv6net(&self) -> Ipv6Net {
+        Ipv6Net::new(veth_address_v6(self.listen_port), BRIDGE_NET6.prefix_len()).unwrap()
+    }
+
+    /// Addresses of the inner veth interface. It only gets an IPv6 address
+    /// if the network forwards to IPv6 targets.
+    fn veth_addresses(&self) -> Vec<IpNet> {
+        let mut addresses = vec![self.veth_ipv4net().into()];
+        if self.has_ipv6_forwarding() {
+            addresses.push(self.veth_ipv6net().into());
+        }
+        addresses
+    }
+
+    /// Address NGINX forwards a port mapping to. Traffic has to reach the
+    /// network over the same address family as the target, since it is
+    /// translated by iptables or ip6tables.
+    fn veth_socket(&self, port: u16, target: &SocketAddr) -> SocketAddr {
+        match target {
+            SocketAddr::V4(_) => SocketAddr::new(self.veth_ipv4net().addr().into(), port),
+            SocketAddr::V6(_) => SocketAddr::new(self.veth_ipv6net().addr().into(), port),
+        }
     }
 
     fn port_mappings(&self) -> Vec<(Url, u16, SocketAddr)> {
@@ -84,30 +267,37 @@ impl NetworkStateExt for NetworkState {
             .collect()
     }
 
-    fn port_config(&self) -> PortConfig {
-        PortConfig {
-            interface_in: self.veth_name(),
-            interface_out: self.wgif_name(),
-            ip_source: self.address.first().unwrap().addr(),
-            mappings: self
-                .port_mappings()
-                .iter()
-                .map(|(_, port, sock)| PortMapping {
-                    port_in: *port,
-                    port_out: sock.port(),
-                    ip_out: sock.ip(),
-                })
-                .collect(),
-        }
+    /// IPv4 port forwarding config, used to generate the iptables rules.
+    fn port_config(&self) -> anyhow::Result<PortConfig> {
+        port_config_family(self, false)
+    }
+
+    /// IPv6 port forwarding config, used to generate the ip6tables rules.
+    fn port_config_v6(&self) -> anyhow::Result<PortConfig> {
+        port_config_family(self, true)
+    }
+
+    fn has_ipv6(&self) -> bool {
+        self.address.iter().any(|address| address.addr().is_ipv6())
+    }
+
+    /// Whether any port mapping of this network has an IPv6 target.
+    fn has_ipv6_forwarding(&self) -> bool {
+        self.port_mappings()
+            .iter()
+            .any(|(_, _, sock)| sock.is_ipv6())
     }
 }
 
 pub trait PeerStateExt {
-    fn to_config(&self, public_key: &Pubkey) -> String;
+    fn to_config(&self, public_key: &Pubkey, default_keepalive: u16) -> String;
 }
 
 impl PeerStateExt for PeerState {
-    fn to_config(&self, public_key: &Pubkey) -> String {
+    /// Generate the peer section of the WireGuard config. The persistent
+    /// keepalive is taken from the peer if it sets one, otherwise the
+    /// `default_keepalive` (the network's or the gateway's default) is used.
+    fn to_config(&self, public_key: &Pubkey, default_keepalive: u16) -> String {
         let mut config = String::new();
         use std::fmt::Write;
         writeln!(config, "[Peer]").unwrap();
@@ -127,7 +317,10 @@ impl PeerStateExt for PeerState {
         if let Some(endpoint) = self.endpoint {
             writeln!(config, "Endpoint = {}", endpoint).unwrap();
         }
-        writeln!(config, "PersistentKeepalive = 25").unwrap();
+        let keepalive = self.persistent_keepalive.unwrap_or(default_keepalive);
+        if keepalive > 0 {
+            writeln!(config, "PersistentKeepalive = {}", keepalive).unwrap();
+        }
         config
     }
 }
@@ -136,9 +329,13 @@ impl PeerStateExt for PeerState {
 pub struct Forwarding {
     https_forwarding: BTreeMap<String, String>,
     https_upstream: BTreeMap<String, Vec<SocketAddr>>,
+    https_options: BTreeMap<String, ProxyOptions>,
     http_forwarding: BTreeMap<String, String>,
     http_upstream: BTreeMap<String, Vec<SocketAddr>>,
+    http_options: BTreeMap<String, ProxyOptions>,
     ssh_forwarding: BTreeMap<String, SocketAddr>,
+    ssh_options: BTreeMap<String, ProxyOptions>,
+    listen: Vec<String>,
 }
 
 impl Forwarding {
@@ -148,19 +345,25 @@ impl Forwarding {
         }
     }
 
-    pub fn add(&mut self, network: &NetworkState) {
-        for (url, port, _sock) in &network.port_mappings() {
-            let sock = SocketAddr::new(network.veth_ipv4net().addr().into(), *port);
+    /// Add the forwarding of a network, failing if one of its SSH services
+    /// cannot be forwarded.
+    pub fn add(&mut self, network: &NetworkState) -> anyhow::Result<()> {
+        for (url, port, target) in &network.port_mappings() {
+            let sock = network.veth_socket(*port, target);
+            let options = network.proxy_options.get(url).cloned().unwrap_or_default();
             match url.scheme() {
-                "https" => self.add_https(url, sock),
-                "http" => self.add_http(url, sock),
-                "ssh" => self.add_ssh(url, sock),
+                "https" => self.add_https(url, sock, &options),
+                "http" => self.add_http(url, sock, &options),
+                "ssh" => self.add_ssh(url, sock, &options)?,
                 _other => error!("Unrecognized URL scheme: {}", url),
             }
         }
+        Ok(())
     }
 
-    pub fn add_https(&mut self, url: &Url, socket: SocketAddr) {
+    /// Add HTTPS forwarding for the host of the URL. If multiple entries
+    /// forward the same host, the options of the first one are used.
+    pub fn add_https(&mut self, url: &Url, socket: SocketAddr, options: &ProxyOptions) {
         let host = url.host_str().unwrap();
         let upstream = self
             .https_forwarding
@@ -174,6 +377,9 @@ impl Forwarding {
                     )
                 )
             });
+        self.https_options
+            .entry(upstream.to_string())
+            .or_insert_with(|| options.clone());
         let servers = self
             .https_upstream
             .entry(upstream.to_string())
@@ -181,7 +387,9 @@ impl Forwarding {
         servers.push(socket);
     }
 
-    pub fn add_http(&mut self, url: &Url, socket: SocketAddr) {
+    /// Add HTTP forwarding for the host of the URL. If multiple entries
+    /// forward the same host, the options of the first one are used.
+    pub fn add_http(&mut self, url: &Url, socket: SocketAddr, options: &ProxyOptions) {
         let host = url.host_str().unwrap();
         let upstream = self
             .http_forwarding
@@ -195,6 +403,9 @@ impl Forwarding {
                     )
                 )
             });
+        self.http_options
+            .entry(upstream.to_string())
+            .or_insert_with(|| options.clone());
         let servers = self
             .http_upstream
             .entry(upstream.to_string())
@@ -202,12 +413,51 @@ impl Forwarding {
         servers.push(socket);
     }
 
-    pub fn add_ssh(&mut self, _url: &Url, _socket: SocketAddr) {}
+    /// Add SSH forwarding for the port of the URL. SSH carries no host
+    /// name that could be used for routing, so every forwarded SSH service
+    /// needs its own port, which must not be used by another entry or by
+    /// HTTP or HTTPS forwarding.
+    pub fn add_ssh(
+        &mut self,
+        url: &Url,
+        socket: SocketAddr,
+        options: &ProxyOptions,
+    ) -> anyhow::Result<()> {
+        let port = url
+            .port()
+            .ok_or_else(|| anyhow!("Missing port in SSH forwarding URL {url}"))?;
+        if port == HTTP_PORT || port == HTTPS_PORT {
+            return Err(anyhow!(
+                "SSH forwarding port {port} of {url} is used for HTTP or HTTPS forwarding"
+            ));
+        }
+        let port = port.to_string();
+        if self.ssh_forwarding.contains_key(&port) {
+            return Err(anyhow!(
+                "SSH forwarding port {port} of {url} is already in use"
+            ));
+        }
+        self.ssh_forwarding.insert(port.clone(), socket);
+        self.ssh_options.insert(port, options.clone());
+        Ok(())
+    }
+
+    /// Only listen on these addresses for forwarded services, instead of
+    /// all addresses.
+    pub fn set_listen(&mut self, addresses: &[IpAddr]) {
+        self.listen = addresses
+            .iter()
+            .map(|address| match address {
+                IpAddr::V4(address) => address.to_string(),
+                IpAddr::V6(address) => format!("[{address}]"),
+            })
+            .collect();
+    }
 
     pub fn add_custom(&mut self, url: &Url, socket: SocketAddr) {
         match url.scheme() {
-            "https" => self.add_https(url, socket),
-            "http" => self.add_http(url, socket),
+            "https" => self.add_https(url, socket, &ProxyOptions::default()),
+            "http" => self.add_http(url, socket, &ProxyOptions::default()),
             _other => error!("Unrecognized URL scheme: {}", url),
         }
     }
@@ -229,8 +479,10 @@ impl FromStr for NetworkStats {
         let network_stats = lines.next().ok_or(anyhow!("Missing network line"))?;
         let components: Vec<&str> = network_stats.split('\t').collect();
         if components.len() != 4 {
-            println!("{:?}", components);
-            return Err(anyhow!("Wrong network stats line len"));
+            return Err(anyhow!(
+                "Wrong network stats line len: {} instead of 4",
+                components.len()
+            ));
         }
         Ok(NetworkStats {
             private_key: Privkey::from_str(components[0])?,
@@ -275,7 +527,10 @@ impl FromStr for PeerStats {
     fn from_str(output: &str) -> Result<Self, Self::Err> {
         let components: Vec<&str> = output.split('\t').collect();
         if components.len() != 8 {
-            return Err(anyhow!("Wrong network stats line len"));
+            return Err(anyhow!(
+                "Wrong peer stats line len: {} instead of 8",
+                components.len()
+            ));
         }
         Ok(PeerStats {
             public_key: Pubkey::from_str(components[0])?,
@@ -315,7 +570,7 @@ impl FromStr for PeerStats {
             persistent_keepalive: if components[7] == "off" {
                 None
             } else {
-                Some(components[4].parse()?)
+                Some(components[7].parse()?)
             },
         })
     }
@@ -327,8 +582,377 @@ impl PeerStats {
     }
 }
 
+/// How WireGuard is provided on this host.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WireguardMode {
+    /// Kernel module
+    Kernel,
+    /// Userspace implementation (`wireguard-go`)
+    Userspace,
+}
+
+/// WireGuard availability, detected on startup.
+#[derive(Serialize, Clone, Debug)]
+pub struct WireguardInfo {
+    /// Whether the kernel module or the userspace implementation is used
+    pub mode: WireguardMode,
+    /// Version of the kernel module or userspace implementation, if known
+    pub version: Option<String>,
+    /// Version of the `wg` tool
+    pub tools: String,
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct NetnsItem {
     pub name: String,
     pub id: Option<usize>,
 }
+
+/// Traffic kept until the manager acknowledges it, so that traffic which
+/// occurs while the manager is disconnected is not lost.
+///
+/// Every time slice gets a cursor, increasing by one. The manager
+/// acknowledges all traffic up to a cursor, and on reconnect all traffic that
+/// was not acknowledged yet is sent again. At most a limited number of time
+/// slices within a time window are kept, older ones are dropped.
+#[derive(Clone, Debug, Default)]
+pub struct TrafficBuffer {
+    /// Cursor of the next time slice
+    next: u64,
+    /// Buffered time slices with the time they were added
+    entries: VecDeque<(SystemTime, BufferedTraffic)>,
+}
+
+impl TrafficBuffer {
+    /// Add a time slice, dropping the oldest ones beyond the limit or
+    /// window. Returns its cursor.
+    pub fn push(
+        &mut self,
+        traffic: TrafficInfo,
+        now: SystemTime,
+        limit: usize,
+        window: Duration,
+    ) -> u64 {
+        let cursor = self.next;
+        self.next += 1;
+        self.entries
+            .push_back((now, BufferedTraffic { cursor, traffic }));
+        while let Some((time, entry)) = self.entries.front() {
+            let expired = now.duration_since(*time).unwrap_or_default() > window;
+            if self.entries.len() <= limit && !expired {
+                break;
+            }
+            warn!(
+                "Dropping unacknowledged traffic with cursor {}",
+                entry.cursor
+            );
+            self.entries.pop_front();
+        }
+        cursor
+    }
+
+    /// Acknowledge all time slices up to and including the cursor.
+    pub fn ack(&mut self, cursor: u64) {
+        self.entries.retain(|(_, entry)| entry.cursor > cursor);
+    }
+
+    /// Time slices after the cursor, or all of them if none is given.
+    pub fn after(&self, cursor: Option<u64>) -> Vec<BufferedTraffic> {
+        self.entries
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| cursor.map(|cursor| entry.cursor > cursor).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network_line(privkey: &Privkey) -> String {
+        format!("{}\t{}\t51820\toff", privkey, privkey.pubkey())
+    }
+
+    fn network(address: &[&str], proxy: &[(&str, &str)]) -> NetworkState {
+        let mut network_proxy: BTreeMap<Url, Vec<SocketAddr>> = BTreeMap::new();
+        for (url, target) in proxy {
+            network_proxy
+                .entry(url.parse().unwrap())
+                .or_default()
+                .push(target.parse().unwrap());
+        }
+        NetworkState {
+            private_key: Privkey::generate(),
+            listen_port: 51820,
+            mtu: None,
+            address: address
+                .iter()
+                .map(|address| address.parse().unwrap())
+                .collect(),
+            peers: BTreeMap::new(),
+            proxy: network_proxy,
+            proxy_options: BTreeMap::new(),
+            require_psk: false,
+            default_keepalive: None,
+            endpoint_families: vec![],
+            assign_addresses: false,
+            egress_policy: None,
+        }
+    }
+
+    #[test]
+    fn network_stats_parse() {
+        let privkey = Privkey::generate();
+        let peer = Privkey::generate().pubkey();
+        let dump = format!(
+            "{}\n{}\t(none)\t192.0.2.1:51820\t10.0.0.2/32\t1600000000\t100\t200\t25",
+            network_line(&privkey),
+            peer
+        );
+        let stats = NetworkStats::from_str(&dump).unwrap();
+        assert_eq!(stats.public_key, privkey.pubkey());
+        assert_eq!(stats.listen_port(), 51820);
+        assert_eq!(stats.fwmark, None);
+        assert_eq!(stats.peers().len(), 1);
+        assert_eq!(stats.peers()[0].public_key, peer);
+        assert_eq!(stats.peers()[0].transfer(), (100, 200));
+        assert_eq!(stats.peers()[0].persistent_keepalive, Some(25));
+    }
+
+    #[test]
+    fn network_stats_parse_empty() {
+        assert!(NetworkStats::from_str("").is_err());
+    }
+
+    #[test]
+    fn network_stats_parse_truncated() {
+        let privkey = Privkey::generate();
+        let line = network_line(&privkey);
+        let (truncated, _) = line.rsplit_once('\t').unwrap();
+        assert!(NetworkStats::from_str(truncated).is_err());
+
+        let peer = Privkey::generate().pubkey();
+        let dump = format!("{line}\n{peer}\t(none)\t(none)\t10.0.0.2/32\t0");
+        assert!(NetworkStats::from_str(&dump).is_err());
+    }
+
+    #[test]
+    fn netns_port_parse() {
+        assert_eq!(parse_netns_port("network-", "network-51820"), Some(51820));
+        assert_eq!(parse_netns_port("gw1-network-", "gw1-network-1"), Some(1));
+    }
+
+    #[test]
+    fn netns_port_parse_malformed() {
+        for name in [
+            "network-",
+            "network-abc",
+            "network-51820x",
+            "network--1",
+            "network-+1",
+            "network-051820",
+            "network-65536",
+            "other-51820",
+            "gw1-network-51820",
+        ] {
+            assert_eq!(parse_netns_port("network-", name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn forwarding_add_ssh() {
+        let mut forwarding = Forwarding::new();
+        let first: SocketAddr = "10.0.0.2:22".parse().unwrap();
+        let second: SocketAddr = "10.0.0.3:22".parse().unwrap();
+        let options = ProxyOptions::default();
+        forwarding
+            .add_ssh(
+                &"ssh://host.example.com:2222".parse().unwrap(),
+                first,
+                &options,
+            )
+            .unwrap();
+        forwarding
+            .add_ssh(
+                &"ssh://other.example.com:2223".parse().unwrap(),
+                second,
+                &options,
+            )
+            .unwrap();
+        assert_eq!(
+            forwarding.ssh_forwarding,
+            BTreeMap::from([("2222".to_string(), first), ("2223".to_string(), second)])
+        );
+    }
+
+    #[test]
+    fn forwarding_add_ssh_conflicts() {
+        let mut forwarding = Forwarding::new();
+        let first: SocketAddr = "10.0.0.2:22".parse().unwrap();
+        let second: SocketAddr = "10.0.0.3:22".parse().unwrap();
+        let options = ProxyOptions::default();
+        let mut add =
+            |url: &str, socket| forwarding.add_ssh(&url.parse().unwrap(), socket, &options);
+        assert!(add("ssh://host.example.com", first).is_err());
+        assert!(add("ssh://host.example.com:443", first).is_err());
+        assert!(add("ssh://host.example.com:80", first).is_err());
+        add("ssh://host.example.com:2222", first).unwrap();
+        assert!(add("ssh://other.example.com:2222", second).is_err());
+        assert_eq!(
+            forwarding.ssh_forwarding,
+            BTreeMap::from([("2222".to_string(), first)])
+        );
+    }
+
+    #[test]
+    fn forwarding_ssh_allow_from() {
+        let mut forwarding = Forwarding::new();
+        let options = ProxyOptions {
+            allow_from: vec!["192.0.2.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+        forwarding
+            .add_ssh(
+                &"ssh://host.example.com:2222".parse().unwrap(),
+                "10.0.0.2:22".parse().unwrap(),
+                &options,
+            )
+            .unwrap();
+        let context = tera::Context::from_serialize(&forwarding).unwrap();
+        let config = crate::gateway::TERA_TEMPLATES
+            .render("nginx.conf", &context)
+            .unwrap();
+        assert!(config.contains("allow 192.0.2.0/24;"));
+        assert!(config.contains("deny all;"));
+    }
+
+    #[test]
+    fn port_config_families() {
+        let network = network(
+            &["10.0.0.1/24", "fd00::1/64"],
+            &[
+                ("https://a.example.com", "10.0.0.2:443"),
+                ("https://b.example.com", "[fd00::2]:443"),
+            ],
+        );
+        let config = network.port_config().unwrap();
+        assert_eq!(config.ip_source, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(config.mappings.len(), 1);
+        assert_eq!(config.mappings[0].port_in, PORT_MAPPING_START);
+        assert_eq!(
+            config.mappings[0].ip_out,
+            "10.0.0.2".parse::<IpAddr>().unwrap()
+        );
+        let config = network.port_config_v6().unwrap();
+        assert_eq!(config.ip_source, Some("fd00::1".parse().unwrap()));
+        assert_eq!(config.mappings.len(), 1);
+        assert_eq!(config.mappings[0].port_in, PORT_MAPPING_START + 1);
+        assert_eq!(
+            config.mappings[0].ip_out,
+            "fd00::2".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn port_config_no_address() {
+        let forwarding = network(&[], &[("https://a.example.com", "10.0.0.2:443")]);
+        assert!(forwarding.port_config().is_err());
+        let config = forwarding.port_config_v6().unwrap();
+        assert!(config.ip_source.is_none());
+        assert!(config.mappings.is_empty());
+
+        let empty = network(&[], &[]);
+        assert!(empty.port_config().unwrap().ip_source.is_none());
+        assert!(empty.port_config_v6().unwrap().ip_source.is_none());
+    }
+
+    #[test]
+    fn port_config_ipv6_only() {
+        let ipv6 = network(
+            &["fd00::1/64"],
+            &[("https://a.example.com", "[fd00::2]:443")],
+        );
+        let config = ipv6.port_config().unwrap();
+        assert!(config.ip_source.is_none());
+        assert!(config.mappings.is_empty());
+        let config = ipv6.port_config_v6().unwrap();
+        assert_eq!(config.ip_source, Some("fd00::1".parse().unwrap()));
+        assert_eq!(config.mappings.len(), 1);
+
+        let ipv4_target = network(
+            &["fd00::1/64"],
+            &[("https://a.example.com", "10.0.0.2:443")],
+        );
+        assert!(ipv4_target.port_config().is_err());
+    }
+
+    fn cursors(buffer: &TrafficBuffer, cursor: Option<u64>) -> Vec<u64> {
+        buffer
+            .after(cursor)
+            .iter()
+            .map(|entry| entry.cursor)
+            .collect()
+    }
+
+    #[test]
+    fn traffic_buffer_limit() {
+        let mut buffer = TrafficBuffer::default();
+        let window = Duration::from_secs(60);
+        for cursor in 0..5 {
+            assert_eq!(
+                buffer.push(TrafficInfo::new(0), UNIX_EPOCH, 3, window),
+                cursor
+            );
+        }
+        assert_eq!(cursors(&buffer, None), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn traffic_buffer_window() {
+        let mut buffer = TrafficBuffer::default();
+        let window = Duration::from_secs(60);
+        buffer.push(TrafficInfo::new(0), UNIX_EPOCH, 10, window);
+        buffer.push(
+            TrafficInfo::new(0),
+            UNIX_EPOCH + Duration::from_secs(30),
+            10,
+            window,
+        );
+        buffer.push(
+            TrafficInfo::new(0),
+            UNIX_EPOCH + Duration::from_secs(61),
+            10,
+            window,
+        );
+        assert_eq!(cursors(&buffer, None), vec![1, 2]);
+    }
+
+    #[test]
+    fn traffic_buffer_ack_inclusive() {
+        let mut buffer = TrafficBuffer::default();
+        let window = Duration::from_secs(60);
+        for _ in 0..4 {
+            buffer.push(TrafficInfo::new(0), UNIX_EPOCH, 10, window);
+        }
+        buffer.ack(1);
+        assert_eq!(cursors(&buffer, None), vec![2, 3]);
+
+        // cursors keep increasing after acknowledging
+        assert_eq!(buffer.push(TrafficInfo::new(0), UNIX_EPOCH, 10, window), 4);
+    }
+
+    #[test]
+    fn traffic_buffer_after() {
+        let mut buffer = TrafficBuffer::default();
+        let window = Duration::from_secs(60);
+        for _ in 0..4 {
+            buffer.push(TrafficInfo::new(0), UNIX_EPOCH, 10, window);
+        }
+        assert_eq!(cursors(&buffer, None), vec![0, 1, 2, 3]);
+        assert_eq!(cursors(&buffer, Some(1)), vec![2, 3]);
+        assert_eq!(cursors(&buffer, Some(3)), Vec::<u64>::new());
+    }
+}
@@ -0,0 +1,1143 @@
+//! Wrappers around the command-line tools the gateway runs. Every wrapper
+//! runs the executables given in [`Paths`], and fails with the error output
+//! of the tool.
+
+use crate::types::{NetworkStats, WireguardMode};
+use anyhow::{anyhow, Context, Result};
+use fractal_gateway_client::InterfaceStats;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use wireguard_keys::Pubkey;
+
+pub const IPTABLES_SAVE_PATH: &'static str = "iptables-save";
+pub const IPTABLES_RESTORE_PATH: &'static str = "iptables-restore";
+pub const IP6TABLES_SAVE_PATH: &'static str = "ip6tables-save";
+pub const IP6TABLES_RESTORE_PATH: &'static str = "ip6tables-restore";
+pub const WIREGUARD_GO_PATH: &'static str = "wireguard-go";
+pub const MODPROBE_PATH: &'static str = "modprobe";
+pub const NETNS_ETC_PATH: &'static str = "/etc/netns";
+
+/// How often to try reading the stats of a wireguard interface. While an
+/// interface is being reconfigured, `wg` can briefly print empty or partial
+/// output.
+const WIREGUARD_STATS_ATTEMPTS: usize = 3;
+
+/// Time to wait between attempts at reading the stats of a wireguard
+/// interface.
+const WIREGUARD_STATS_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Paths of the executables run by the wrappers. Executables given without
+/// a directory are looked up in `PATH`.
+#[derive(Clone, Debug)]
+pub struct Paths {
+    pub ip: PathBuf,
+    pub wg: PathBuf,
+    pub nginx: PathBuf,
+    /// Limits how many commands run at the same time, to avoid overwhelming
+    /// the kernel with netlink requests.
+    pub limit: Arc<Semaphore>,
+}
+
+/// Directory whose files `ip netns exec` bind-mounts over `/etc` for commands
+/// run inside of the network namespace.
+pub fn netns_etc_path(netns: &str) -> PathBuf {
+    Path::new(NETNS_ETC_PATH).join(netns)
+}
+
+/// Create a command which runs the program inside the network namespace, or
+/// in the root namespace if none is supplied.
+fn netns_command(paths: &Paths, netns: Option<&str>, program: impl AsRef<OsStr>) -> Command {
+    match netns {
+        Some(netns) => {
+            let mut command = Command::new(&paths.ip);
+            command.arg("netns").arg("exec").arg(netns).arg(program);
+            command
+        }
+        None => Command::new(program),
+    }
+}
+
+/// Create an `ip` command operating on the network namespace, or on the root
+/// namespace if none is supplied.
+fn ip_command(paths: &Paths, netns: Option<&str>) -> Command {
+    let mut command = Command::new(&paths.ip);
+    if let Some(netns) = netns {
+        command.arg("-n").arg(netns);
+    }
+    command
+}
+
+/// Run a command and return its trimmed standard output. Fails with the
+/// standard error output if the command does not succeed. Waits for a permit
+/// of [`Paths::limit`] first.
+async fn command_output(paths: &Paths, command: &mut Command) -> Result<String> {
+    let _permit = paths.limit.acquire().await?;
+    let output = command
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .output()
+        .await
+        .context("Running command")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Command failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Returns the version string of the installed `wg` tool.
+pub async fn wireguard_tools_version(paths: &Paths) -> Result<String> {
+    command_output(paths, Command::new(&paths.wg).arg("--version"))
+        .await
+        .context("Getting wireguard-tools version")
+}
+
+/// Returns the version string of the installed userspace `wireguard-go`
+/// implementation.
+pub async fn wireguard_go_version(paths: &Paths) -> Result<String> {
+    command_output(paths, Command::new(WIREGUARD_GO_PATH).arg("--version"))
+        .await
+        .context("Getting wireguard-go version")
+}
+
+/// Remove a peer from a wireguard interface.
+pub async fn wireguard_peer_remove(
+    paths: &Paths,
+    netns: &str,
+    interface: &str,
+    peer: &Pubkey,
+) -> Result<()> {
+    command_output(
+        paths,
+        netns_command(paths, Some(netns), &paths.wg)
+            .arg("set")
+            .arg(interface)
+            .arg("peer")
+            .arg(peer.to_string())
+            .arg("remove"),
+    )
+    .await
+    .with_context(|| format!("Removing peer {peer} from {interface}"))?;
+    Ok(())
+}
+
+/// Create a wireguard interface inside of a network namespace. Without the
+/// kernel module, `wireguard-go` is started in the namespace, which creates
+/// the interface and runs in the background until it is deleted.
+pub async fn wireguard_create(
+    paths: &Paths,
+    netns: &str,
+    name: &str,
+    mode: WireguardMode,
+) -> Result<()> {
+    let mut command = match mode {
+        WireguardMode::Kernel => {
+            let mut command = ip_command(paths, Some(netns));
+            command
+                .arg("link")
+                .arg("add")
+                .arg("dev")
+                .arg(name)
+                .arg("type")
+                .arg("wireguard");
+            command
+        }
+        WireguardMode::Userspace => {
+            let mut command = netns_command(paths, Some(netns), WIREGUARD_GO_PATH);
+            command.arg(name);
+            command
+        }
+    };
+    command_output(paths, &mut command)
+        .await
+        .with_context(|| format!("Creating wireguard interface {name} in {netns}"))?;
+    Ok(())
+}
+
+/// Check whether a wireguard interface exists inside of a network namespace.
+pub async fn wireguard_exists(paths: &Paths, netns: &str, name: &str) -> Result<bool> {
+    link_exists(paths, Some(netns), name).await
+}
+
+/// Messages with which `wg` rejects a config. Retrying does not help for
+/// these, unlike for a config file or interface that is not there yet.
+const WIREGUARD_CONFIG_ERRORS: &[&str] = &[
+    "Line unrecognized",
+    "Configuration parsing error",
+    "Key is not the correct length or format",
+    "Unable to parse",
+];
+
+/// Whether a `wg` error means that the config was rejected.
+pub fn wireguard_config_rejected(error: &anyhow::Error) -> bool {
+    let message = format!("{error:#}");
+    WIREGUARD_CONFIG_ERRORS
+        .iter()
+        .any(|rejected| message.contains(rejected))
+}
+
+/// Sync the config of a wireguard interface with a config file, given as
+/// seen from inside of the network namespace, failing with the standard
+/// error output of `wg`.
+pub async fn wireguard_syncconf(
+    paths: &Paths,
+    netns: &str,
+    interface: &str,
+    config: &Path,
+) -> Result<()> {
+    command_output(
+        paths,
+        netns_command(paths, Some(netns), &paths.wg)
+            .arg("syncconf")
+            .arg(interface)
+            .arg(config),
+    )
+    .await
+    .with_context(|| format!("Syncing config of {interface}"))?;
+    Ok(())
+}
+
+/// Error for a wireguard interface for which `wg` printed no stats at all,
+/// which happens while it is being set up or reconfigured.
+#[derive(thiserror::Error, Debug)]
+#[error("Interface {0} is not ready")]
+pub struct InterfaceNotReady(pub String);
+
+/// Error for a wireguard interface whose `wg show dump` output could not be
+/// parsed, which happens when `wg` races with a reconfiguration.
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid wireguard dump of {0}")]
+pub struct InvalidDump(pub String);
+
+/// Whether reading the stats of a wireguard interface failed in a way which
+/// is expected to go away once it is reconfigured.
+fn wireguard_stats_transient(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<InterfaceNotReady>().is_some()
+        || error.downcast_ref::<InvalidDump>().is_some()
+}
+
+/// Read the stats of a wireguard interface, retrying after a short delay if
+/// `wg` printed nothing or something incomplete, since that happens while
+/// the interface is being reconfigured. Other errors, such as a missing
+/// interface, are returned right away.
+async fn wireguard_stats_retry<T, F, R>(interface: &str, read: F) -> Result<T>
+where
+    F: Fn() -> R,
+    R: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match read().await {
+            Ok(stats) => return Ok(stats),
+            Err(e) if attempt < WIREGUARD_STATS_ATTEMPTS && wireguard_stats_transient(&e) => {
+                log::debug!(
+                    "Reading stats of {interface} failed, retrying ({attempt}/{WIREGUARD_STATS_ATTEMPTS}): {e:#}"
+                );
+                attempt += 1;
+                tokio::time::sleep(WIREGUARD_STATS_BACKOFF).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Read the configuration and state of a wireguard interface, including its
+/// private key, using `wg show dump`. Fails with [`InterfaceNotReady`] if
+/// `wg` keeps printing nothing, and with a parse error if its output keeps
+/// being malformed.
+pub async fn wireguard_dump(paths: &Paths, netns: &str, interface: &str) -> Result<NetworkStats> {
+    wireguard_stats_retry(interface, || async move {
+        let output = command_output(
+            paths,
+            netns_command(paths, Some(netns), &paths.wg)
+                .arg("show")
+                .arg(interface)
+                .arg("dump"),
+        )
+        .await
+        .with_context(|| format!("Dumping {interface}"))?;
+        if output.is_empty() {
+            return Err(InterfaceNotReady(interface.to_string()).into());
+        }
+        output
+            .parse::<NetworkStats>()
+            .map_err(|e| e.context(InvalidDump(interface.to_string())))
+    })
+    .await
+}
+
+/// Read the stats of every wireguard interface in every network namespace by
+/// running `wg show all dump` in all of them with a single `ip -all netns
+/// exec`, instead of spawning one process per network. Returns the stats by
+/// network namespace and interface name.
+pub async fn wireguard_dump_all(
+    paths: &Paths,
+) -> Result<BTreeMap<String, BTreeMap<String, NetworkStats>>> {
+    let output = command_output(
+        paths,
+        Command::new(&paths.ip)
+            .arg("-all")
+            .arg("netns")
+            .arg("exec")
+            .arg(&paths.wg)
+            .arg("show")
+            .arg("all")
+            .arg("dump"),
+    )
+    .await
+    .context("Dumping wireguard interfaces of all network namespaces")?;
+    parse_dump_all(&output)
+}
+
+/// Parse the output of `ip -all netns exec wg show all dump`. `ip` prints a
+/// `netns: <name>` line before the output of every namespace, and `wg`
+/// prefixes every line with the name of the interface it belongs to.
+fn parse_dump_all(output: &str) -> Result<BTreeMap<String, BTreeMap<String, NetworkStats>>> {
+    let mut lines: BTreeMap<&str, BTreeMap<&str, Vec<&str>>> = BTreeMap::new();
+    let mut netns = None;
+    for line in output.lines().filter(|line| !line.is_empty()) {
+        if let Some(name) = line.strip_prefix("netns: ") {
+            lines.entry(name).or_default();
+            netns = Some(name);
+            continue;
+        }
+        let netns = netns.ok_or(anyhow!("Missing network namespace for {line:?}"))?;
+        let (interface, rest) = line
+            .split_once('\t')
+            .ok_or(anyhow!("Missing interface name in {line:?}"))?;
+        lines
+            .entry(netns)
+            .or_default()
+            .entry(interface)
+            .or_default()
+            .push(rest);
+    }
+
+    lines
+        .into_iter()
+        .map(|(netns, interfaces)| {
+            let interfaces = interfaces
+                .into_iter()
+                .map(|(interface, lines)| {
+                    let stats = lines
+                        .join("\n")
+                        .parse::<NetworkStats>()
+                        .map_err(|e| e.context(InvalidDump(interface.to_string())))
+                        .with_context(|| format!("Parsing stats of {interface} in {netns}"))?;
+                    Ok((interface.to_string(), stats))
+                })
+                .collect::<Result<_>>()?;
+            Ok((netns.to_string(), interfaces))
+        })
+        .collect()
+}
+
+/// Check whether NGINX is installed.
+pub async fn nginx_available(paths: &Paths) -> bool {
+    command_output(paths, Command::new(&paths.nginx).arg("-v"))
+        .await
+        .is_ok()
+}
+
+/// Test the NGINX configuration with `nginx -t`, failing with the error
+/// output of NGINX if it is invalid.
+pub async fn nginx_test(paths: &Paths) -> Result<()> {
+    command_output(paths, Command::new(&paths.nginx).arg("-t"))
+        .await
+        .context("Testing NGINX config")?;
+    Ok(())
+}
+
+/// Reload the NGINX configuration.
+pub async fn nginx_reload(paths: &Paths) -> Result<()> {
+    command_output(paths, Command::new(&paths.nginx).arg("-s").arg("reload"))
+        .await
+        .context("Reloading NGINX")?;
+    Ok(())
+}
+
+/// Load a kernel module.
+pub async fn modprobe(paths: &Paths, module: &str) -> Result<()> {
+    command_output(paths, Command::new(MODPROBE_PATH).arg(module))
+        .await
+        .with_context(|| format!("Loading kernel module {module}"))?;
+    Ok(())
+}
+
+/// Create a network namespace, failing with the error output of `ip`.
+pub async fn netns_add(paths: &Paths, name: &str) -> Result<()> {
+    command_output(
+        paths,
+        Command::new(&paths.ip).arg("netns").arg("add").arg(name),
+    )
+    .await
+    .with_context(|| format!("Creating netns {name}"))?;
+    Ok(())
+}
+
+/// Delete a network namespace, failing with the error output of `ip`.
+pub async fn netns_del(paths: &Paths, name: &str) -> Result<()> {
+    command_output(
+        paths,
+        Command::new(&paths.ip).arg("netns").arg("del").arg(name),
+    )
+    .await
+    .with_context(|| format!("Deleting netns {name}"))?;
+    Ok(())
+}
+
+/// Network namespace as reported by `ip -j netns list`.
+#[derive(Deserialize)]
+struct IpNetns {
+    name: String,
+}
+
+/// List the names of all network namespaces.
+pub async fn netns_list(paths: &Paths) -> Result<Vec<String>> {
+    let output = command_output(
+        paths,
+        Command::new(&paths.ip).arg("-j").arg("netns").arg("list"),
+    )
+    .await
+    .context("Listing network namespaces")?;
+    // without any namespaces, ip prints nothing at all
+    if output.is_empty() {
+        return Ok(vec![]);
+    }
+    let netns: Vec<IpNetns> =
+        serde_json::from_str(&output).context("Parsing network namespaces")?;
+    Ok(netns.into_iter().map(|netns| netns.name).collect())
+}
+
+/// Check whether a network namespace exists.
+pub async fn netns_exists(paths: &Paths, name: &str) -> Result<bool> {
+    Ok(netns_list(paths).await?.iter().any(|netns| netns == name))
+}
+
+/// Create a bridge interface, failing with the error output of `ip`.
+pub async fn bridge_add(paths: &Paths, netns: Option<&str>, name: &str) -> Result<()> {
+    let mut command = ip_command(paths, netns);
+    command_output(
+        paths,
+        command
+            .arg("link")
+            .arg("add")
+            .arg(name)
+            .arg("type")
+            .arg("bridge"),
+    )
+    .await
+    .with_context(|| format!("Creating bridge {name}"))?;
+    Ok(())
+}
+
+/// Create a veth pair with one half in the root namespace and its peer in
+/// the network namespace, failing with the error output of `ip`.
+pub async fn veth_add(paths: &Paths, netns: &str, outer: &str, inner: &str) -> Result<()> {
+    command_output(
+        paths,
+        Command::new(&paths.ip)
+            .arg("link")
+            .arg("add")
+            .arg(outer)
+            .arg("type")
+            .arg("veth")
+            .arg("peer")
+            .arg("name")
+            .arg(inner)
+            .arg("netns")
+            .arg(netns),
+    )
+    .await
+    .with_context(|| format!("Creating veth pair {outer} and {inner} in {netns}"))?;
+    Ok(())
+}
+
+/// Check whether a network interface exists.
+pub async fn link_exists(paths: &Paths, netns: Option<&str>, interface: &str) -> Result<bool> {
+    let mut command = ip_command(paths, netns);
+    let status = command
+        .arg("link")
+        .arg("show")
+        .arg("dev")
+        .arg(interface)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await
+        .context("Running ip link show")?;
+    Ok(status.success())
+}
+
+/// Delete a network interface.
+pub async fn link_del(paths: &Paths, netns: Option<&str>, interface: &str) -> Result<()> {
+    let mut command = ip_command(paths, netns);
+    command_output(
+        paths,
+        command.arg("link").arg("del").arg("dev").arg(interface),
+    )
+    .await
+    .with_context(|| format!("Deleting interface {interface}"))?;
+    Ok(())
+}
+
+/// Delete a veth pair by one of its halves. The kernel removes the other
+/// half and any bridge membership along with it.
+pub async fn veth_del(paths: &Paths, netns: Option<&str>, interface: &str) -> Result<()> {
+    link_del(paths, netns, interface)
+        .await
+        .with_context(|| format!("Deleting veth {interface}"))
+}
+
+/// Delete a bridge interface. Interfaces attached to it are detached, but
+/// not deleted.
+pub async fn bridge_del(paths: &Paths, netns: Option<&str>, name: &str) -> Result<()> {
+    let mut command = ip_command(paths, netns);
+    command_output(
+        paths,
+        command
+            .arg("link")
+            .arg("del")
+            .arg("dev")
+            .arg(name)
+            .arg("type")
+            .arg("bridge"),
+    )
+    .await
+    .with_context(|| format!("Deleting bridge {name}"))?;
+    Ok(())
+}
+
+/// Addresses of a link as reported by `ip -j address show`.
+#[derive(Deserialize)]
+struct IpAddrShow {
+    #[serde(default)]
+    addr_info: Vec<IpAddrInfo>,
+}
+
+#[derive(Deserialize)]
+struct IpAddrInfo {
+    local: IpAddr,
+    prefixlen: u8,
+}
+
+/// List the addresses of a network interface.
+pub async fn addr_list(paths: &Paths, netns: Option<&str>, interface: &str) -> Result<Vec<IpNet>> {
+    let mut command = ip_command(paths, netns);
+    let output = command_output(
+        paths,
+        command
+            .arg("-j")
+            .arg("address")
+            .arg("show")
+            .arg("dev")
+            .arg(interface),
+    )
+    .await
+    .with_context(|| format!("Listing addresses of {interface}"))?;
+    let links: Vec<IpAddrShow> = serde_json::from_str(&output).context("Parsing addresses")?;
+    links
+        .into_iter()
+        .flat_map(|link| link.addr_info)
+        .map(|info| {
+            IpNet::new(info.local, info.prefixlen)
+                .with_context(|| format!("Invalid address {}/{}", info.local, info.prefixlen))
+        })
+        .collect()
+}
+
+/// Add an address to a network interface.
+pub async fn addr_add(
+    paths: &Paths,
+    netns: Option<&str>,
+    interface: &str,
+    addr: IpNet,
+) -> Result<()> {
+    let mut command = ip_command(paths, netns);
+    command_output(
+        paths,
+        command
+            .arg("address")
+            .arg("add")
+            .arg(addr.to_string())
+            .arg("dev")
+            .arg(interface),
+    )
+    .await
+    .with_context(|| format!("Adding address {addr} to {interface}"))?;
+    Ok(())
+}
+
+/// Remove an address from a network interface.
+pub async fn addr_del(
+    paths: &Paths,
+    netns: Option<&str>,
+    interface: &str,
+    addr: IpNet,
+) -> Result<()> {
+    let mut command = ip_command(paths, netns);
+    command_output(
+        paths,
+        command
+            .arg("address")
+            .arg("del")
+            .arg(addr.to_string())
+            .arg("dev")
+            .arg(interface),
+    )
+    .await
+    .with_context(|| format!("Removing address {addr} from {interface}"))?;
+    Ok(())
+}
+
+/// Replace the default route of a network namespace, or of the root
+/// namespace if none is supplied.
+pub async fn route_default_replace(
+    paths: &Paths,
+    netns: Option<&str>,
+    via: IpAddr,
+    interface: &str,
+) -> Result<()> {
+    let mut command = ip_command(paths, netns);
+    command_output(
+        paths,
+        command
+            .arg("route")
+            .arg("replace")
+            .arg("default")
+            .arg("via")
+            .arg(via.to_string())
+            .arg("dev")
+            .arg(interface),
+    )
+    .await
+    .with_context(|| format!("Setting default route via {via} dev {interface}"))?;
+    Ok(())
+}
+
+/// Remove the default route of a network namespace, or of the root namespace
+/// if none is supplied, if there is one.
+pub async fn route_default_del(paths: &Paths, netns: Option<&str>) -> Result<()> {
+    let output = command_output(
+        paths,
+        ip_command(paths, netns)
+            .arg("-j")
+            .arg("route")
+            .arg("show")
+            .arg("default"),
+    )
+    .await
+    .context("Listing default routes")?;
+    let routes: Vec<serde_json::Value> = if output.is_empty() {
+        vec![]
+    } else {
+        serde_json::from_str(&output).context("Parsing default routes")?
+    };
+    if !routes.is_empty() {
+        command_output(
+            paths,
+            ip_command(paths, netns)
+                .arg("route")
+                .arg("del")
+                .arg("default"),
+        )
+        .await
+        .context("Removing default route")?;
+    }
+    Ok(())
+}
+
+/// Routing rule as reported by `ip -j rule show`.
+#[derive(Deserialize)]
+struct IpRule {
+    priority: u32,
+    src: Option<String>,
+    table: Option<String>,
+}
+
+/// List the source addresses and routing tables of the routing rules with
+/// the given priority in the root namespace.
+pub async fn rule_list(paths: &Paths, priority: u32) -> Result<Vec<(Ipv4Addr, u32)>> {
+    let output = command_output(
+        paths,
+        Command::new(&paths.ip).arg("-j").arg("rule").arg("show"),
+    )
+    .await
+    .context("Listing routing rules")?;
+    let rules: Vec<IpRule> = serde_json::from_str(&output).context("Parsing routing rules")?;
+    Ok(rules
+        .into_iter()
+        .filter(|rule| rule.priority == priority)
+        .filter_map(|rule| Some((rule.src?.parse().ok()?, rule.table?.parse().ok()?)))
+        .collect())
+}
+
+/// Add a routing rule to the root namespace which routes traffic from the
+/// source address by the routing table.
+pub async fn rule_add(paths: &Paths, source: Ipv4Addr, table: u32, priority: u32) -> Result<()> {
+    command_output(
+        paths,
+        Command::new(&paths.ip)
+            .arg("rule")
+            .arg("add")
+            .arg("from")
+            .arg(source.to_string())
+            .arg("lookup")
+            .arg(table.to_string())
+            .arg("priority")
+            .arg(priority.to_string()),
+    )
+    .await
+    .with_context(|| format!("Adding routing rule from {source} to table {table}"))?;
+    Ok(())
+}
+
+/// Remove a routing rule added with [`rule_add`].
+pub async fn rule_del(paths: &Paths, source: Ipv4Addr, table: u32, priority: u32) -> Result<()> {
+    command_output(
+        paths,
+        Command::new(&paths.ip)
+            .arg("rule")
+            .arg("del")
+            .arg("from")
+            .arg(source.to_string())
+            .arg("lookup")
+            .arg(table.to_string())
+            .arg("priority")
+            .arg(priority.to_string()),
+    )
+    .await
+    .with_context(|| format!("Removing routing rule from {source} to table {table}"))?;
+    Ok(())
+}
+
+/// Save the current iptables state of a network namespace.
+pub async fn iptables_save(paths: &Paths, netns: Option<&str>) -> Result<String> {
+    command_output(paths, &mut netns_command(paths, netns, IPTABLES_SAVE_PATH))
+        .await
+        .context("Saving iptables state")
+}
+
+/// Restore an iptables state in a network namespace.
+pub async fn iptables_restore(paths: &Paths, netns: Option<&str>, state: &str) -> Result<()> {
+    tables_restore(paths, netns, IPTABLES_RESTORE_PATH, state)
+        .await
+        .context("Restoring iptables state")
+}
+
+/// Save the current ip6tables state of a network namespace.
+pub async fn ip6tables_save(paths: &Paths, netns: Option<&str>) -> Result<String> {
+    command_output(paths, &mut netns_command(paths, netns, IP6TABLES_SAVE_PATH))
+        .await
+        .context("Saving ip6tables state")
+}
+
+/// Restore an ip6tables state in a network namespace.
+pub async fn ip6tables_restore(paths: &Paths, netns: Option<&str>, state: &str) -> Result<()> {
+    tables_restore(paths, netns, IP6TABLES_RESTORE_PATH, state)
+        .await
+        .context("Restoring ip6tables state")
+}
+
+/// Run `iptables-restore` or `ip6tables-restore` in a network namespace,
+/// passing the state on standard input.
+async fn tables_restore(
+    paths: &Paths,
+    netns: Option<&str>,
+    program: &str,
+    state: &str,
+) -> Result<()> {
+    let _permit = paths.limit.acquire().await?;
+    let mut child = netns_command(paths, netns, program)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Running {program}"))?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or(anyhow!("Missing stdin for {program}"))?;
+    stdin.write_all(state.as_bytes()).await?;
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Command failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Flags of a link as reported by `ip -j link show`, such as `UP`,
+/// `LOWER_UP` or `POINTOPOINT`.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct LinkFlags {
+    #[serde(default)]
+    pub flags: Vec<String>,
+}
+
+impl LinkFlags {
+    /// Whether the link has the given flag set.
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|set| set == flag)
+    }
+}
+
+/// State of a link as reported by `ip -j link show`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct InterfaceShow {
+    pub mtu: Option<usize>,
+    pub operstate: Option<String>,
+    pub master: Option<String>,
+    #[serde(flatten)]
+    pub flags: LinkFlags,
+}
+
+impl InterfaceShow {
+    /// Whether the operational state of the link is `DOWN`.
+    pub fn is_down(&self) -> bool {
+        self.operstate.as_deref() == Some("DOWN")
+    }
+}
+
+/// Read the state of a network interface.
+pub async fn interface_show(
+    paths: &Paths,
+    netns: Option<&str>,
+    interface: &str,
+) -> Result<InterfaceShow> {
+    let mut command = ip_command(paths, netns);
+    let output = command_output(
+        paths,
+        command
+            .arg("-j")
+            .arg("link")
+            .arg("show")
+            .arg("dev")
+            .arg(interface),
+    )
+    .await
+    .with_context(|| format!("Reading state of {interface}"))?;
+    let links: Vec<InterfaceShow> = serde_json::from_str(&output).context("Parsing link state")?;
+    links
+        .into_iter()
+        .next()
+        .ok_or(anyhow!("Missing state for {interface}"))
+}
+
+/// Read the flags of a network interface. Unlike the operational state
+/// returned by [`interface_show`], these tell whether the interface was
+/// brought up, and what kind of link it is.
+pub async fn link_flags(paths: &Paths, netns: Option<&str>, interface: &str) -> Result<LinkFlags> {
+    Ok(interface_show(paths, netns, interface).await?.flags)
+}
+
+/// Set the MTU of a network interface.
+pub async fn interface_mtu(
+    paths: &Paths,
+    netns: Option<&str>,
+    interface: &str,
+    mtu: usize,
+) -> Result<()> {
+    let mut command = ip_command(paths, netns);
+    command_output(
+        paths,
+        command
+            .arg("link")
+            .arg("set")
+            .arg("dev")
+            .arg(interface)
+            .arg("mtu")
+            .arg(mtu.to_string()),
+    )
+    .await
+    .with_context(|| format!("Setting MTU of {interface} to {mtu}"))?;
+    Ok(())
+}
+
+/// Bring a network interface up.
+pub async fn interface_up(paths: &Paths, netns: Option<&str>, interface: &str) -> Result<()> {
+    let mut command = ip_command(paths, netns);
+    command_output(
+        paths,
+        command
+            .arg("link")
+            .arg("set")
+            .arg("dev")
+            .arg(interface)
+            .arg("up"),
+    )
+    .await
+    .with_context(|| format!("Bringing {interface} up"))?;
+    Ok(())
+}
+
+/// Read the master of a network interface, such as the bridge it is attached
+/// to.
+pub async fn link_get_master(
+    paths: &Paths,
+    netns: Option<&str>,
+    interface: &str,
+) -> Result<Option<String>> {
+    Ok(interface_show(paths, netns, interface).await?.master)
+}
+
+/// Attach a network interface to a master, such as a bridge.
+pub async fn link_set_master(
+    paths: &Paths,
+    netns: Option<&str>,
+    interface: &str,
+    master: &str,
+) -> Result<()> {
+    let mut command = ip_command(paths, netns);
+    command_output(
+        paths,
+        command
+            .arg("link")
+            .arg("set")
+            .arg("dev")
+            .arg(interface)
+            .arg("master")
+            .arg(master),
+    )
+    .await
+    .with_context(|| format!("Setting master of {interface} to {master}"))?;
+    Ok(())
+}
+
+/// Statistics of a link as reported by `ip -s -j link show`.
+#[derive(Deserialize)]
+struct IpLinkStats {
+    stats64: IpLinkStats64,
+}
+
+#[derive(Deserialize)]
+struct IpLinkStats64 {
+    rx: IpLinkCounters,
+    tx: IpLinkCounters,
+}
+
+#[derive(Deserialize)]
+struct IpLinkCounters {
+    packets: usize,
+    errors: usize,
+    dropped: usize,
+}
+
+/// Read the kernel packet, error and drop counters of a network interface.
+pub async fn interface_stats(
+    paths: &Paths,
+    netns: Option<&str>,
+    interface: &str,
+) -> Result<InterfaceStats> {
+    let mut command = ip_command(paths, netns);
+    let output = command_output(
+        paths,
+        command
+            .arg("-s")
+            .arg("-j")
+            .arg("link")
+            .arg("show")
+            .arg("dev")
+            .arg(interface),
+    )
+    .await
+    .with_context(|| format!("Reading statistics of {interface}"))?;
+    let links: Vec<IpLinkStats> =
+        serde_json::from_str(&output).context("Parsing interface statistics")?;
+    let link = links
+        .into_iter()
+        .next()
+        .ok_or(anyhow!("Missing statistics for {interface}"))?;
+    Ok(InterfaceStats {
+        rx_packets: link.stats64.rx.packets,
+        rx_errors: link.stats64.rx.errors,
+        rx_dropped: link.stats64.rx.dropped,
+        tx_packets: link.stats64.tx.packets,
+        tx_errors: link.stats64.tx.errors,
+        tx_dropped: link.stats64.tx.dropped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{fake_executable, LOG_ARGS};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn wireguard_stats_retry_transient() {
+        let attempts = AtomicUsize::new(0);
+        let result: Result<()> = wireguard_stats_retry("wg51820", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(InterfaceNotReady("wg51820".into()).into())
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), WIREGUARD_STATS_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn wireguard_stats_retry_other_errors() {
+        let attempts = AtomicUsize::new(0);
+        let result: Result<()> = wireguard_stats_retry("wg51820", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow!("Device not found"))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn dump_all_parse() {
+        let privkey = wireguard_keys::Privkey::generate();
+        let peer = wireguard_keys::Privkey::generate().pubkey();
+        let output = format!(
+            "\nnetns: network-51820\n\
+             wg51820\t{privkey}\t{}\t51820\toff\n\
+             wg51820\t{peer}\t(none)\t(none)\t10.0.0.2/32\t0\t0\t0\toff\n\
+             \nnetns: other\n",
+            privkey.pubkey()
+        );
+        let stats = parse_dump_all(&output).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert!(stats["other"].is_empty());
+        let network = &stats["network-51820"]["wg51820"];
+        assert_eq!(network.listen_port(), 51820);
+        assert_eq!(network.peers().len(), 1);
+        assert_eq!(network.peers()[0].public_key, peer);
+    }
+
+    #[test]
+    fn dump_all_parse_malformed() {
+        // output before any namespace header
+        assert!(parse_dump_all("wg51820\tkey").is_err());
+        // interface line which is cut off
+        assert!(parse_dump_all("netns: network-51820\nwg51820\tkey").is_err());
+    }
+
+    /// Paths which run the given `ip`.
+    fn paths(ip: PathBuf) -> Paths {
+        Paths {
+            ip,
+            wg: "wg".into(),
+            nginx: "nginx".into(),
+            limit: Arc::new(Semaphore::new(1)),
+        }
+    }
+
+    #[tokio::test]
+    async fn netns_list_runs_configured_ip() {
+        let dir = fake_executable("netns-list", "ip", "echo '[{\"name\":\"network-51820\"}]'");
+        let netns = netns_list(&paths(dir.join("ip"))).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(netns.unwrap(), vec!["network-51820".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn netns_add_error_output() {
+        let dir = fake_executable(
+            "netns-add",
+            "ip",
+            "echo 'Cannot create namespace file: File exists' >&2\nexit 1",
+        );
+        let result = netns_add(&paths(dir.join("ip")), "network-51820").await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("Creating netns network-51820"));
+        assert!(error.contains("Cannot create namespace file: File exists"));
+    }
+
+    #[tokio::test]
+    async fn teardown_arguments() {
+        let dir = fake_executable("teardown", "ip", LOG_ARGS);
+        let paths = paths(dir.join("ip"));
+        let addr: IpNet = "172.99.0.2/16".parse().unwrap();
+        let netns = Some("network-51820");
+        let result: Result<()> = async {
+            addr_del(&paths, None, "veth51820", addr).await?;
+            addr_del(&paths, netns, "veth51820", addr).await?;
+            link_del(&paths, None, "veth51820").await?;
+            link_del(&paths, netns, "wg51820").await?;
+            bridge_del(&paths, None, "ensbr0").await?;
+            bridge_del(&paths, netns, "br0").await?;
+            Ok(())
+        }
+        .await;
+        let args = std::fs::read_to_string(dir.join("args"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert_eq!(
+            args.unwrap().lines().collect::<Vec<_>>(),
+            vec![
+                "address del 172.99.0.2/16 dev veth51820",
+                "-n network-51820 address del 172.99.0.2/16 dev veth51820",
+                "link del dev veth51820",
+                "-n network-51820 link del dev wg51820",
+                "link del dev ensbr0 type bridge",
+                "-n network-51820 link del dev br0 type bridge",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn wireguard_create_arguments() {
+        let dir = fake_executable("wireguard-create", "ip", LOG_ARGS);
+        let paths = paths(dir.join("ip"));
+        let result: Result<()> = async {
+            wireguard_create(&paths, "network-51820", "wg51820", WireguardMode::Kernel).await?;
+            wireguard_create(&paths, "network-51820", "wg51820", WireguardMode::Userspace).await?;
+            Ok(())
+        }
+        .await;
+        let args = std::fs::read_to_string(dir.join("args"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert_eq!(
+            args.unwrap().lines().collect::<Vec<_>>(),
+            vec![
+                "-n network-51820 link add dev wg51820 type wireguard",
+                "netns exec network-51820 wireguard-go wg51820",
+            ]
+        );
+    }
+
+    #[test]
+    fn link_show_parse() {
+        let output = r#"[{"ifindex":4,"ifname":"wg51820","flags":["POINTOPOINT","NOARP","UP","LOWER_UP"],"mtu":1420,"qdisc":"noqueue","master":"br0","operstate":"UNKNOWN","group":"default","txqlen":1000,"link_type":"none"}]"#;
+        let links: Vec<InterfaceShow> = serde_json::from_str(output).unwrap();
+        let link = &links[0];
+        assert_eq!(link.mtu, Some(1420));
+        assert_eq!(link.master.as_deref(), Some("br0"));
+        assert!(!link.is_down());
+        assert!(link.flags.has_flag("UP"));
+        assert!(link.flags.has_flag("NOARP"));
+        assert!(!link.flags.has_flag("BROADCAST"));
+    }
+
+    #[test]
+    fn link_show_parse_down() {
+        let output = r#"[{"ifindex":4,"ifname":"wg51820","mtu":1420,"operstate":"DOWN"}]"#;
+        let links: Vec<InterfaceShow> = serde_json::from_str(output).unwrap();
+        let link = &links[0];
+        assert!(link.is_down());
+        assert!(link.master.is_none());
+        assert!(link.flags.flags.is_empty());
+        assert!(!link.flags.has_flag("UP"));
+    }
+}
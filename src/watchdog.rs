@@ -1,66 +1,419 @@
-use crate::types::NETNS_PREFIX;
+use crate::types::{
+    parse_netns_port, DisconnectStrategy, NetworkStateExt, NetworkStats, PeerStats,
+    DEFAULT_PERSISTENT_KEEPALIVE, VETH_PREFIX, WIREGUARD_PREFIX,
+};
+use crate::util;
 use crate::Global;
+use crate::Options;
 use anyhow::{Context, Result};
 use fractal_gateway_client::{
-    GatewayEvent, GatewayPeerConnectedEvent, GatewayPeerDisconnectedEvent,
-    GatewayPeerEndpointEvent, Traffic, TrafficInfo,
+    ConnectedPeer, GatewayConnected, GatewayEvent, GatewayNetworkNeverConnectedEvent,
+    GatewayPeerConnectedEvent, GatewayPeerDisconnectedEvent, GatewayPeerEndpointEvent,
+    GatewayPeerHandshakeEvent, GatewayPeerQuotaExceededEvent, GatewayStatus, HandshakeRate,
+    NetworkInterfaceStats, Traffic, TrafficInfo,
 };
-use fractal_networking_wrappers::*;
 use log::*;
-use std::collections::{BTreeMap, HashSet};
-use std::time::SystemTime;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use wireguard_keys::Pubkey;
 
-/// Minimum amount of traffic to be recorded. This exists because we don't
-/// need to store a traffic entry if no traffic has occured. But because of
-/// the PersistentKeepalive, there will always be some amount of traffic.
-/// Hence, we can set a lower limit of 1000 bytes, below which we don't store
-/// it. The traffic will still accumulate, so no information is lost.
-pub const TRAFFIC_MINIMUM: usize = 1024;
-
 pub const WIREGUARD_HANDSHAKE_TIMEOUT: u64 = 3 * 60;
 
-type PeerCache = BTreeMap<u16, BTreeMap<Pubkey, PeerStats>>;
+/// Size of a WireGuard keepalive message, as counted in the transfer stats.
+const KEEPALIVE_MESSAGE_SIZE: usize = 32;
+
+/// Size of a WireGuard handshake initiation and response message together.
+const HANDSHAKE_MESSAGES_SIZE: usize = 148 + 92;
+
+/// Interval in seconds at which WireGuard renews sessions with a handshake.
+const REKEY_AFTER_TIME: u64 = 120;
+
+/// Estimate how much traffic a peer generates in an interval when it is idle
+/// and only sending keepalives.
+///
+/// This counts one keepalive message in each direction per keepalive
+/// interval, plus one handshake per rekey interval (WireGuard keeps renewing
+/// the session while keepalives are sent). Both are rounded up so that an
+/// idle peer is not mistaken for an active one.
+pub fn keepalive_traffic(interval: Duration, keepalive: u16) -> usize {
+    let seconds = interval.as_secs();
+    let keepalives = match keepalive {
+        0 => 0,
+        keepalive => (seconds / keepalive as u64 + 1) as usize,
+    };
+    let handshakes = (seconds / REKEY_AFTER_TIME + 1) as usize;
+    2 * keepalives * KEEPALIVE_MESSAGE_SIZE + handshakes * HANDSHAKE_MESSAGES_SIZE
+}
+
+type PeerCache = BTreeMap<u16, BTreeMap<Pubkey, CachedPeer>>;
+
+/// State of a peer as of the previous watchdog run.
+#[derive(Clone, Debug)]
+pub struct CachedPeer {
+    /// Stats of the peer in the previous run.
+    stats: PeerStats,
+    /// Transfer counters of the peer when its traffic was last recorded.
+    recorded: Traffic,
+    /// Endpoint last reported in an endpoint event, or the endpoint the
+    /// peer had when it was first seen.
+    endpoint_reported: Option<SocketAddr>,
+    /// When the last endpoint event for this peer was emitted.
+    endpoint_reported_at: Option<SystemTime>,
+}
+
+/// State kept by the watchdog between runs.
+#[derive(Default)]
+pub struct WatchdogState {
+    /// Peer stats from the previous run, by listen port.
+    peers: PeerCache,
+    /// Data usage of peers within the current quota period.
+    quota: QuotaUsage,
+    /// Whether networks have ever had a handshake, by listen port.
+    networks: BTreeMap<u16, NetworkHandshakes>,
+}
+
+/// Tracks whether any peer of a network has ever completed a handshake.
+#[derive(Clone, Debug)]
+pub struct NetworkHandshakes {
+    /// When the watchdog first saw this network.
+    first_seen: SystemTime,
+    /// Whether any peer has had a handshake since.
+    connected: bool,
+    /// Whether a never-connected event was emitted for this network.
+    reported: bool,
+    /// Time, configured peers and peers with a recent handshake of the
+    /// watchdog runs within the handshake window.
+    samples: VecDeque<(SystemTime, usize, usize)>,
+}
+
+impl NetworkHandshakes {
+    /// Handshake rate over the samples in the window.
+    fn rate(&self, window: Duration) -> HandshakeRate {
+        HandshakeRate {
+            window: window.as_secs() as usize,
+            peers: self.samples.iter().map(|(_, peers, _)| peers).sum(),
+            handshakes: self.samples.iter().map(|(_, _, recent)| recent).sum(),
+        }
+    }
+}
+
+/// Data usage of peers within the current quota period, keyed by network
+/// and peer public key.
+///
+/// Usage is tracked in memory from the start of the watchdog, and reset
+/// every quota period (`--quota-period`). Restarting the gateway starts a
+/// new period. When quotas are enforced, peers that exceed their quota are
+/// removed from their network until the period resets, at which point their
+/// networks' WireGuard configs are applied again to add them back.
+#[derive(Default)]
+pub struct QuotaUsage {
+    /// Start of the current quota period.
+    start: Option<SystemTime>,
+    /// Bytes transferred by each peer in this period.
+    usage: BTreeMap<(Pubkey, Pubkey), usize>,
+    /// Peers which exceeded their quota in this period.
+    exceeded: BTreeSet<(Pubkey, Pubkey)>,
+    /// Quota events which were not sent yet, since no manager was connected.
+    unreported: VecDeque<GatewayPeerQuotaExceededEvent>,
+}
 
 /// Start watchdog process that repeatedly checks the state of the system, with
 /// a configurable interval.
 pub async fn watchdog(global: &Global) -> Result<()> {
     info!("Launching watchdog every {}s", global.watchdog.as_secs());
     let mut interval = tokio::time::interval(global.watchdog);
-    let mut peer_cache = PeerCache::new();
+    let mut state = WatchdogState::default();
+    let mut paused = false;
     loop {
         interval.tick().await;
-        watchdog_run(&global, &mut peer_cache).await?;
+        if global.watchdog_paused() {
+            if !paused {
+                info!("Watchdog paused");
+                paused = true;
+            }
+            continue;
+        }
+
+        // the first run after resuming only refreshes the cached counters,
+        // so that traffic which occurred while paused neither shows up as a
+        // spike nor counts towards quotas.
+        let resumed = std::mem::replace(&mut paused, false);
+        if resumed {
+            info!("Watchdog resumed");
+        }
+        watchdog_run(&global, &mut state, resumed, SystemTime::now()).await?;
     }
 }
 
-pub async fn watchdog_run(global: &Global, cache: &mut PeerCache) -> Result<()> {
+/// Run the watchdog once. All time-dependent decisions of this run, such as
+/// handshake timeouts and traffic timestamps, are made relative to `now`. If
+/// the watchdog was `resumed`, the traffic of this run is neither sent nor
+/// counted towards quotas.
+pub async fn watchdog_run(
+    global: &Global,
+    state: &mut WatchdogState,
+    resumed: bool,
+    now: SystemTime,
+) -> Result<()> {
     info!("Running watchdog");
-    let netns_items = netns_list().await.context("Listing network namespaces")?;
+    let netns_items = util::netns_list(global.paths())
+        .await
+        .context("Listing network namespaces")?;
     let mut traffic = TrafficInfo::new(0);
+
+    // read the stats of all networks at once. networks missing from it are
+    // read one at a time, which is also what happens if this fails.
+    let mut dump = match util::wireguard_dump_all(global.paths()).await {
+        Ok(dump) => dump,
+        Err(e) => {
+            warn!("Error reading stats of all networks, reading them one at a time: {e:#}");
+            BTreeMap::new()
+        }
+    };
+
+    // effective keepalive intervals of peers, by listen port
+    let keepalives: BTreeMap<u16, BTreeMap<Pubkey, u16>> = {
+        let config = global.lock().lock().await;
+        config
+            .iter()
+            .map(|(port, network)| {
+                let peers = network
+                    .peers
+                    .iter()
+                    .map(|(pubkey, peer)| (*pubkey, network.peer_keepalive(peer)))
+                    .collect();
+                (*port, peers)
+            })
+            .collect()
+    };
+
+    let mut ports = BTreeSet::new();
+    let mut interfaces = BTreeMap::new();
+    let mut status = GatewayStatus::default();
     for netns in &netns_items {
-        if netns.name.starts_with(NETNS_PREFIX) {
-            match watchdog_netns(global, &mut traffic, cache, &netns.name).await {
-                Ok(_) => {}
+        // namespaces that are not named after a network are skipped
+        if let Some(port) = parse_netns_port(&global.options().netns_prefix, netns) {
+            let dumped = dump
+                .get_mut(netns)
+                .and_then(|interfaces| interfaces.remove(&format!("{WIREGUARD_PREFIX}{port}")));
+            match watchdog_netns(
+                global,
+                &mut traffic,
+                &mut state.peers,
+                &keepalives,
+                netns,
+                port,
+                dumped,
+                now,
+            )
+            .await
+            {
+                Ok(stats) => {
+                    ports.insert(stats.listen_port());
+                    status.networks.insert(
+                        port,
+                        crate::gateway::network_status(global, port, &stats, now),
+                    );
+                    let peers = keepalives
+                        .get(&stats.listen_port())
+                        .map(|peers| peers.len())
+                        .unwrap_or(0);
+                    if let Err(e) =
+                        watchdog_handshakes(global, &mut state.networks, &stats, peers, now).await
+                    {
+                        error!("Error in watchdog_handshakes: {:?}", e);
+                    }
+                    match watchdog_interfaces(global.paths(), netns, stats.listen_port()).await {
+                        Ok(counters) => {
+                            interfaces.insert(stats.listen_port(), counters);
+                        }
+                        Err(e) => error!("Error in watchdog_interfaces: {:?}", e),
+                    }
+                }
                 Err(e) => error!("Error in watchdog_netns: {:?}", e),
             }
         }
     }
-    global.traffic_broadcast.send(traffic)?;
+    state.networks.retain(|port, _| ports.contains(port));
+    global.interfaces_sender.send(interfaces)?;
+    let window = global.options().handshake_window;
+    global.handshakes_sender.send(
+        state
+            .networks
+            .iter()
+            .map(|(port, network)| (*port, network.rate(window)))
+            .collect(),
+    )?;
+    global.connected_sender.send(connected_peers(&state.peers))?;
+    if !resumed {
+        if let Err(e) = watchdog_quota(global, &traffic, &mut state.quota, now).await {
+            error!("Error in watchdog_quota: {:?}", e);
+        }
+        global.buffer_traffic(&traffic, now);
+
+        // nobody is subscribed while the manager is disconnected
+        let _ = global.traffic_broadcast.send(traffic);
+    }
+    let _ = global.status_broadcast.send(status);
+    Ok(())
+}
+
+/// Account the traffic of this run towards the peers' data quotas, emitting
+/// an event (and removing the peer, if quotas are enforced) when a peer
+/// exceeds its quota. Events that cannot be sent are kept and sent again in
+/// the next run.
+pub async fn watchdog_quota(
+    global: &Global,
+    traffic: &TrafficInfo,
+    quota: &mut QuotaUsage,
+    now: SystemTime,
+) -> Result<()> {
+    let start = *quota.start.get_or_insert(now);
+    if now.duration_since(start).unwrap_or_default() >= global.options().quota_period {
+        quota_reset(global, quota, now).await?;
+    }
+
+    // quotas of all configured peers, with the port of their network
+    let quotas: BTreeMap<(Pubkey, Pubkey), (u16, usize)> = {
+        let config = global.lock().lock().await;
+        config
+            .iter()
+            .flat_map(|(port, network)| {
+                let network_key = network.private_key.pubkey();
+                network.peers.iter().filter_map(move |(peer, state)| {
+                    state
+                        .quota_bytes
+                        .map(|limit| ((network_key, *peer), (*port, limit)))
+                })
+            })
+            .collect()
+    };
+
+    for (network, network_traffic) in &traffic.networks {
+        for (peer, device) in &network_traffic.devices {
+            let key = (*network, *peer);
+            let usage = quota.usage.entry(key).or_default();
+            *usage += device.traffic.rx + device.traffic.tx;
+            let usage = *usage;
+
+            let (port, limit) = match quotas.get(&key) {
+                Some(quota) => *quota,
+                None => continue,
+            };
+            if usage <= limit || !quota.exceeded.insert(key) {
+                continue;
+            }
+
+            warn!("Peer {peer} of network {network} exceeded its quota of {limit} bytes");
+            if global.options().enforce_quota {
+                global.quota_block(port, *peer);
+                let netns = format!("{}{port}", global.options().netns_prefix);
+                let wgif = format!("{WIREGUARD_PREFIX}{port}");
+                util::wireguard_peer_remove(global.paths(), &netns, &wgif, peer)
+                    .await
+                    .context("Removing peer which exceeded its quota")?;
+            }
+
+            // enforcement must not depend on the manager being connected, so
+            // the event is only queued here.
+            quota.unreported.push_back(GatewayPeerQuotaExceededEvent {
+                network: *network,
+                peer: *peer,
+                usage,
+                quota: limit,
+            });
+        }
+    }
+
+    // like never-connected events, quota events are retried on every run
+    // until they are sent.
+    while let Some(event) = quota.unreported.front() {
+        global
+            .event(&GatewayEvent::PeerQuotaExceeded(event.clone()))
+            .await
+            .context("Sending quota event")?;
+        quota.unreported.pop_front();
+    }
+
+    Ok(())
+}
+
+/// Start a new quota period, adding back any peers that were removed for
+/// exceeding their quota.
+async fn quota_reset(global: &Global, quota: &mut QuotaUsage, now: SystemTime) -> Result<()> {
+    info!("Starting new quota period");
+    quota.start = Some(now);
+    quota.usage.clear();
+    quota.exceeded.clear();
+
+    let ports = global.quota_unblock_all();
+    if !ports.is_empty() {
+        let config = global.lock().lock().await;
+        for port in ports {
+            if let Some(network) = config.get(&port) {
+                crate::gateway::apply_wireguard(global, network)
+                    .await
+                    .context("Adding back peers after quota reset")?;
+            }
+        }
+    }
+
     Ok(())
 }
 
+/// Build a snapshot of the connected peers out of the peer cache. Peers whose
+/// latest handshake is older than the timeout have it reset to `None` when
+/// they are cached, so every peer that still has a handshake is connected.
+pub fn connected_peers(cache: &PeerCache) -> GatewayConnected {
+    let mut connected = GatewayConnected::default();
+    for (port, peers) in cache.iter() {
+        let peers: BTreeMap<Pubkey, ConnectedPeer> = peers
+            .iter()
+            .filter_map(|(pubkey, peer)| {
+                let handshake = peer
+                    .stats
+                    .latest_handshake?
+                    .duration_since(UNIX_EPOCH)
+                    .ok()?;
+                Some((
+                    *pubkey,
+                    ConnectedPeer {
+                        endpoint: peer.stats.endpoint,
+                        latest_handshake: handshake.as_secs() as usize,
+                    },
+                ))
+            })
+            .collect();
+        if !peers.is_empty() {
+            connected.insert(*port, peers);
+        }
+    }
+    connected
+}
+
+/// Account the traffic and connection changes of the peers of a network,
+/// using its `dumped` stats if they were read already.
 pub async fn watchdog_netns(
     global: &Global,
     traffic: &mut TrafficInfo,
     cache: &mut PeerCache,
+    keepalives: &BTreeMap<u16, BTreeMap<Pubkey, u16>>,
     netns: &str,
-) -> Result<()> {
+    port: u16,
+    dumped: Option<NetworkStats>,
+    now: SystemTime,
+) -> Result<NetworkStats> {
     // pull wireguard stats
-    let wgif = format!("wg{}", &netns[8..]);
-    let stats = wireguard_stats(&netns, &wgif)
-        .await
-        .context("Fetching wireguard stats")?;
+    let stats = match dumped {
+        Some(stats) => stats,
+        None => {
+            let wgif = format!("{WIREGUARD_PREFIX}{port}");
+            util::wireguard_dump(global.paths(), netns, &wgif)
+                .await
+                .context("Fetching wireguard stats")?
+        }
+    };
 
     // if not exists, create and fetch cache for this wireguard network
     let entry = cache
@@ -71,7 +424,12 @@ pub async fn watchdog_netns(
     let mut peers = HashSet::new();
     for peer in stats.peers() {
         peers.insert(peer.public_key);
-        match watchdog_peer(global, traffic, entry, &stats, &peer).await {
+        let keepalive = keepalives
+            .get(&stats.listen_port())
+            .and_then(|peers| peers.get(&peer.public_key))
+            .copied()
+            .unwrap_or(DEFAULT_PERSISTENT_KEEPALIVE);
+        match watchdog_peer(global, traffic, entry, &stats, &peer, keepalive, now).await {
             Ok(_) => {}
             Err(e) => error!("Error in watchdog_peer: {:?}", e),
         }
@@ -92,26 +450,169 @@ pub async fn watchdog_netns(
             .event(&GatewayEvent::PeerDisconnected(
                 GatewayPeerDisconnectedEvent {
                     network: stats.public_key,
+                    listen_port: stats.listen_port(),
                     peer: peer,
                 },
             ))
             .await?;
     }
 
+    Ok(stats)
+}
+
+/// Read the kernel counters of the WireGuard and the inner veth interface of
+/// a network.
+pub async fn watchdog_interfaces(
+    paths: &util::Paths,
+    netns: &str,
+    port: u16,
+) -> Result<NetworkInterfaceStats> {
+    Ok(NetworkInterfaceStats {
+        wireguard: util::interface_stats(paths, Some(netns), &format!("{WIREGUARD_PREFIX}{port}"))
+            .await?,
+        veth: util::interface_stats(paths, Some(netns), &format!("{VETH_PREFIX}{port}")).await?,
+    })
+}
+
+/// Emit an event for networks which have been up for longer than the
+/// configured threshold without any of their peers ever completing a
+/// handshake, which usually means that UDP traffic is blocked or keys are
+/// wrong. Networks without peers are not reported.
+///
+/// Also records how many of the network's configured peers had a recent
+/// handshake, for the handshake rate.
+pub async fn watchdog_handshakes(
+    global: &Global,
+    networks: &mut BTreeMap<u16, NetworkHandshakes>,
+    stats: &NetworkStats,
+    peers: usize,
+    now: SystemTime,
+) -> Result<()> {
+    let network = networks
+        .entry(stats.listen_port())
+        .or_insert_with(|| NetworkHandshakes {
+            first_seen: now,
+            connected: false,
+            reported: false,
+            samples: VecDeque::new(),
+        });
+
+    let recent = stats
+        .peers()
+        .iter()
+        .filter_map(|peer| peer.latest_handshake)
+        .filter(|handshake| {
+            now.duration_since(*handshake)
+                .map(|duration| duration.as_secs() <= WIREGUARD_HANDSHAKE_TIMEOUT)
+                .unwrap_or(false)
+        })
+        .count();
+    network.samples.push_back((now, peers, recent));
+    let window = global.options().handshake_window;
+    while let Some((time, _, _)) = network.samples.front() {
+        if now.duration_since(*time).unwrap_or_default() <= window {
+            break;
+        }
+        network.samples.pop_front();
+    }
+
+    if stats
+        .peers()
+        .iter()
+        .any(|peer| peer.latest_handshake.is_some())
+    {
+        network.connected = true;
+    }
+
+    let elapsed = now.duration_since(network.first_seen).unwrap_or_default();
+    if !network.connected
+        && !network.reported
+        && !stats.peers().is_empty()
+        && elapsed >= global.options().never_connected_timeout
+    {
+        warn!(
+            "Network {} has had no handshakes since {}s",
+            stats.listen_port(),
+            elapsed.as_secs()
+        );
+        global
+            .event(&GatewayEvent::NetworkNeverConnected(
+                GatewayNetworkNeverConnectedEvent {
+                    network: stats.public_key,
+                    since: network
+                        .first_seen
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs() as usize,
+                },
+            ))
+            .await?;
+
+        // only once the event was sent, otherwise it is sent on the next run
+        network.reported = true;
+    }
+
     Ok(())
 }
 
+/// Update the cached state of a peer from its current stats, emitting the
+/// resulting events and recording its traffic.
 pub async fn watchdog_peer(
     global: &Global,
     traffic: &mut TrafficInfo,
-    cache: &mut BTreeMap<Pubkey, PeerStats>,
+    cache: &mut BTreeMap<Pubkey, CachedPeer>,
     stats: &NetworkStats,
     peer: &PeerStats,
+    keepalive: u16,
+    now: SystemTime,
 ) -> Result<()> {
+    let update = peer_update(
+        global.options(),
+        cache.get(&peer.public_key),
+        stats,
+        peer,
+        keepalive,
+        now,
+    )?;
+    for event in &update.events {
+        global.event(event).await?;
+    }
+    if let Some((time, difference)) = update.traffic {
+        traffic.add(stats.public_key, peer.public_key, time, difference);
+    }
+    cache.insert(peer.public_key, update.cached);
+    Ok(())
+}
+
+/// Result of comparing the stats of a peer with its cached state.
+#[derive(Clone, Debug)]
+pub struct PeerUpdate {
+    /// Events to emit for the peer.
+    pub events: Vec<GatewayEvent>,
+    /// Traffic to record for the peer, with its timestamp.
+    pub traffic: Option<(usize, Traffic)>,
+    /// New cached state of the peer.
+    pub cached: CachedPeer,
+}
+
+/// Compare the current stats of a peer with its cached state from the
+/// previous run, without emitting anything. All time-dependent decisions are
+/// made relative to `now`.
+pub fn peer_update(
+    options: &Options,
+    cached: Option<&CachedPeer>,
+    stats: &NetworkStats,
+    peer: &PeerStats,
+    keepalive: u16,
+    now: SystemTime,
+) -> Result<PeerUpdate> {
+    let mut events = vec![];
+    let mut traffic = None;
+
     // set latest_timeout to none if it is too long ago
     let mut peer = peer.clone();
     if let Some(handshake) = peer.latest_handshake {
-        let duration = SystemTime::now().duration_since(handshake);
+        let duration = now.duration_since(handshake);
         if let Ok(duration) = duration {
             if duration.as_secs() > WIREGUARD_HANDSHAKE_TIMEOUT {
                 peer.latest_handshake = None;
@@ -121,76 +622,371 @@ pub async fn watchdog_peer(
         }
     }
 
-    if let Some(previous) = cache.get(&peer.public_key) {
-        let time = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_secs() as usize;
-        if previous.transfer_rx > peer.transfer_rx || previous.transfer_tx > peer.transfer_tx {
-            error!(
-                "Cache invalid for network {} peer {}",
+    // with the traffic strategy, a peer that only sends keepalives is idle,
+    // and is treated like one without a recent handshake.
+    if options.disconnect_strategy == DisconnectStrategy::Traffic {
+        if let Some(previous) = cached {
+            let previous = &previous.stats;
+            let transferred = peer.transfer_rx.saturating_sub(previous.transfer_rx)
+                + peer.transfer_tx.saturating_sub(previous.transfer_tx);
+            if transferred <= keepalive_traffic(options.watchdog, keepalive) {
+                peer.latest_handshake = None;
+            }
+        }
+    }
+
+    let mut endpoint_reported = peer.endpoint;
+    let mut endpoint_reported_at = None;
+    let mut recorded = Traffic::new(peer.transfer_rx, peer.transfer_tx);
+    if let Some(cached) = cached {
+        let previous = &cached.stats;
+        let time = now.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as usize;
+        match recorded.checked_sub(&cached.recorded) {
+            // counters went backwards, so they were reset: skip this sample
+            None => error!(
+                "Traffic counters reset for network {} peer {}",
                 stats.public_key, peer.public_key
-            );
-        } else {
-            // how much traffic has been generated in total?
-            let difference = (previous.transfer_rx - peer.transfer_rx)
-                + (previous.transfer_tx - peer.transfer_tx);
-
-            // only send out traffic if traffic has occured
-            if difference > 0 {
-                let traffic_item = Traffic::new(
-                    peer.transfer_rx - previous.transfer_rx,
-                    peer.transfer_tx - previous.transfer_tx,
-                );
-                traffic.add(stats.public_key, peer.public_key, time, traffic_item);
+            ),
+            // traffic below the minimum accumulates until a later run
+            Some(difference)
+                if difference == Traffic::default()
+                    || difference.rx + difference.tx < options.traffic_minimum =>
+            {
+                recorded = cached.recorded;
+            }
+            Some(difference) => {
+                traffic = Some((time, difference));
             }
         }
 
-        if peer.endpoint != previous.endpoint {
-            if let Some(endpoint) = peer.endpoint {
-                global
-                    .event(&GatewayEvent::Endpoint(GatewayPeerEndpointEvent {
-                        endpoint: endpoint,
-                        network: stats.public_key,
-                        peer: peer.public_key,
-                    }))
-                    .await?;
+        // report endpoint changes, but at most once per cooldown window. If
+        // the endpoint changes again within the window, the latest endpoint
+        // is reported once the window has passed.
+        endpoint_reported = cached.endpoint_reported;
+        endpoint_reported_at = cached.endpoint_reported_at;
+        if let Some(endpoint) = peer.endpoint {
+            let settled = endpoint_reported_at
+                .and_then(|reported_at| now.duration_since(reported_at).ok())
+                .map(|elapsed| elapsed >= options.endpoint_cooldown)
+                .unwrap_or(true);
+            if endpoint_reported != Some(endpoint) && settled {
+                events.push(GatewayEvent::Endpoint(GatewayPeerEndpointEvent {
+                    endpoint: endpoint,
+                    network: stats.public_key,
+                    listen_port: stats.listen_port(),
+                    peer: peer.public_key,
+                }));
+                endpoint_reported = Some(endpoint);
+                endpoint_reported_at = Some(now);
             }
         }
 
         match (previous.latest_handshake, peer.latest_handshake) {
             (Some(_), None) => {
-                global
-                    .event(&GatewayEvent::PeerDisconnected(
-                        GatewayPeerDisconnectedEvent {
-                            network: stats.public_key,
-                            peer: peer.public_key,
-                        },
-                    ))
-                    .await?;
-            }
-            (None, Some(_)) => {
-                global
-                    .event(&GatewayEvent::PeerConnected(GatewayPeerConnectedEvent {
-                        endpoint: peer.endpoint.unwrap(),
+                events.push(GatewayEvent::PeerDisconnected(
+                    GatewayPeerDisconnectedEvent {
                         network: stats.public_key,
+                        listen_port: stats.listen_port(),
                         peer: peer.public_key,
-                    }))
-                    .await?;
+                    },
+                ));
+            }
+            (None, Some(_)) => {
+                events.push(GatewayEvent::PeerConnected(GatewayPeerConnectedEvent {
+                    endpoint: peer.endpoint.unwrap(),
+                    network: stats.public_key,
+                    listen_port: stats.listen_port(),
+                    peer: peer.public_key,
+                }));
+            }
+            (Some(previous), Some(current)) if current > previous => {
+                events.push(GatewayEvent::PeerHandshake(GatewayPeerHandshakeEvent {
+                    network: stats.public_key,
+                    listen_port: stats.listen_port(),
+                    peer: peer.public_key,
+                    time: current.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as usize,
+                }));
             }
             _ => {}
         }
     } else {
         if peer.latest_handshake.is_some() {
-            global
-                .event(&GatewayEvent::PeerConnected(GatewayPeerConnectedEvent {
-                    endpoint: peer.endpoint.unwrap(),
-                    network: stats.public_key,
-                    peer: peer.public_key,
-                }))
-                .await?;
+            events.push(GatewayEvent::PeerConnected(GatewayPeerConnectedEvent {
+                endpoint: peer.endpoint.unwrap(),
+                network: stats.public_key,
+                listen_port: stats.listen_port(),
+                peer: peer.public_key,
+            }));
         }
     }
 
-    cache.insert(peer.public_key, peer);
-    Ok(())
+    Ok(PeerUpdate {
+        events,
+        traffic,
+        cached: CachedPeer {
+            stats: peer,
+            recorded,
+            endpoint_reported,
+            endpoint_reported_at,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{fake_executable, global, options, LOG_ARGS};
+    use fractal_gateway_client::NetworkState;
+    use serde_json::json;
+    use wireguard_keys::Privkey;
+
+    fn network() -> NetworkStats {
+        let private_key = Privkey::generate();
+        NetworkStats {
+            public_key: private_key.pubkey(),
+            private_key,
+            listen_port: 51820,
+            fwmark: None,
+            peers: vec![],
+        }
+    }
+
+    fn peer(latest_handshake: Option<SystemTime>, rx: usize, tx: usize) -> PeerStats {
+        PeerStats {
+            public_key: Privkey::generate().pubkey(),
+            preshared_key: None,
+            endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+            allowed_ips: vec![],
+            latest_handshake,
+            transfer_rx: rx,
+            transfer_tx: tx,
+            persistent_keepalive: None,
+        }
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn peer_update_connected() {
+        let options = options();
+        let network = network();
+        let peer = peer(Some(at(1000)), 0, 0);
+        let update = peer_update(&options, None, &network, &peer, 25, at(1010)).unwrap();
+        assert!(matches!(
+            update.events.as_slice(),
+            [GatewayEvent::PeerConnected(event)] if event.peer == peer.public_key
+        ));
+        assert!(update.traffic.is_none());
+    }
+
+    #[test]
+    fn peer_update_disconnected() {
+        let options = options();
+        let network = network();
+        let mut peer = peer(Some(at(1000)), 0, 0);
+        let connected = peer_update(&options, None, &network, &peer, 25, at(1010)).unwrap();
+
+        // no new handshake within the timeout
+        let now = at(1000 + WIREGUARD_HANDSHAKE_TIMEOUT + 1);
+        let update =
+            peer_update(&options, Some(&connected.cached), &network, &peer, 25, now).unwrap();
+        assert!(matches!(
+            update.events.as_slice(),
+            [GatewayEvent::PeerDisconnected(event)] if event.peer == peer.public_key
+        ));
+
+        // a new handshake connects it again
+        peer.latest_handshake = Some(now);
+        let update = peer_update(&options, Some(&update.cached), &network, &peer, 25, now).unwrap();
+        assert!(matches!(
+            update.events.as_slice(),
+            [GatewayEvent::PeerConnected(_)]
+        ));
+    }
+
+    #[test]
+    fn peer_update_handshake() {
+        let options = options();
+        let network = network();
+        let mut peer = peer(Some(at(1000)), 0, 0);
+        let connected = peer_update(&options, None, &network, &peer, 25, at(1010)).unwrap();
+
+        // an unchanged handshake is not reported
+        let update = peer_update(
+            &options,
+            Some(&connected.cached),
+            &network,
+            &peer,
+            25,
+            at(1040),
+        )
+        .unwrap();
+        assert!(update.events.is_empty());
+
+        // a newer handshake is reported once, with its time
+        peer.latest_handshake = Some(at(1100));
+        let update = peer_update(
+            &options,
+            Some(&update.cached),
+            &network,
+            &peer,
+            25,
+            at(1120),
+        )
+        .unwrap();
+        assert!(matches!(
+            update.events.as_slice(),
+            [GatewayEvent::PeerHandshake(event)]
+                if event.peer == peer.public_key && event.time == 1100
+        ));
+    }
+
+    #[test]
+    fn peer_update_traffic() {
+        let options = options();
+        let network = network();
+        let first = peer(None, 0, 0);
+        let update = peer_update(&options, None, &network, &first, 25, at(1000)).unwrap();
+        assert!(update.traffic.is_none());
+
+        // traffic below the minimum is held back
+        let below = PeerStats {
+            transfer_rx: 100,
+            transfer_tx: 100,
+            ..first.clone()
+        };
+        let update = peer_update(
+            &options,
+            Some(&update.cached),
+            &network,
+            &below,
+            25,
+            at(1030),
+        )
+        .unwrap();
+        assert!(update.traffic.is_none());
+        assert_eq!(update.cached.recorded, Traffic::new(0, 0));
+
+        // and recorded together with the traffic of a later run
+        let above = PeerStats {
+            transfer_rx: 1000,
+            transfer_tx: 500,
+            ..first.clone()
+        };
+        let update = peer_update(
+            &options,
+            Some(&update.cached),
+            &network,
+            &above,
+            25,
+            at(1060),
+        )
+        .unwrap();
+        assert_eq!(update.traffic, Some((1060, Traffic::new(1000, 500))));
+        assert_eq!(update.cached.recorded, Traffic::new(1000, 500));
+
+        // counters going backwards are not recorded
+        let reset = PeerStats {
+            transfer_rx: 10,
+            transfer_tx: 10,
+            ..first
+        };
+        let update = peer_update(
+            &options,
+            Some(&update.cached),
+            &network,
+            &reset,
+            25,
+            at(1090),
+        )
+        .unwrap();
+        assert!(update.traffic.is_none());
+        assert_eq!(update.cached.recorded, Traffic::new(10, 10));
+    }
+
+    #[tokio::test]
+    async fn watchdog_handshakes_reported_once_sent() {
+        let mut options = options();
+        options.never_connected_timeout = Duration::from_secs(60);
+        let global = global(&options, false);
+        let mut stats = network();
+        stats.peers.push(peer(None, 0, 0));
+        let mut networks = BTreeMap::new();
+        watchdog_handshakes(&global, &mut networks, &stats, 1, at(1000))
+            .await
+            .unwrap();
+
+        // the manager is disconnected when the network is first reported
+        assert!(
+            watchdog_handshakes(&global, &mut networks, &stats, 1, at(1060))
+                .await
+                .is_err()
+        );
+
+        // so it is reported again once the manager is connected, and only once
+        let mut events = global.events_broadcast.subscribe();
+        watchdog_handshakes(&global, &mut networks, &stats, 1, at(1120))
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(GatewayEvent::NetworkNeverConnected(event)) if event.since == 1000
+        ));
+        watchdog_handshakes(&global, &mut networks, &stats, 1, at(1180))
+            .await
+            .unwrap();
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn watchdog_quota_enforced_without_manager() {
+        let dir = fake_executable("watchdog-quota", "ip", LOG_ARGS);
+        let mut options = options();
+        options.enforce_quota = true;
+        options.ip_path = dir.join("ip");
+        let global = global(&options, false);
+
+        let private_key = Privkey::generate();
+        let peer = Privkey::generate().pubkey();
+        let network: NetworkState = serde_json::from_value(json!({
+            "private_key": private_key.to_string(),
+            "address": ["10.0.0.1/24"],
+            "peers": { peer.to_string(): { "allowed_ips": [], "quota_bytes": 1000 } },
+            "proxy": {},
+        }))
+        .unwrap();
+        global.lock().lock().await.insert(51820, network);
+
+        // nobody is subscribed to events, so emitting the event fails
+        let mut traffic = TrafficInfo::new(1000);
+        traffic.add(private_key.pubkey(), peer, 1000, Traffic::new(1000, 500));
+        let mut quota = QuotaUsage::default();
+        let result = watchdog_quota(&global, &traffic, &mut quota, at(1000)).await;
+        let args = std::fs::read_to_string(dir.join("args"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.is_err());
+        assert!(global.quota_blocked(51820).contains(&peer));
+        assert_eq!(
+            args.unwrap().trim(),
+            format!("netns exec network-51820 wg set wg51820 peer {peer} remove")
+        );
+
+        // so it is sent once the manager is connected, and only once
+        let mut events = global.events_broadcast.subscribe();
+        let traffic = TrafficInfo::new(1060);
+        watchdog_quota(&global, &traffic, &mut quota, at(1060))
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(GatewayEvent::PeerQuotaExceeded(event)) if event.peer == peer && event.usage == 1500
+        ));
+        watchdog_quota(&global, &traffic, &mut quota, at(1120))
+            .await
+            .unwrap();
+        assert!(events.try_recv().is_err());
+    }
 }
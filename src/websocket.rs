@@ -3,28 +3,50 @@ use anyhow::{anyhow, Result};
 use async_tungstenite::tokio::*;
 use async_tungstenite::tungstenite::handshake::client::Request;
 use async_tungstenite::tungstenite::Message;
-use fractal_gateway_client::{GatewayRequest, GatewayResponse};
+use fractal_gateway_client::{AuditOperation, GatewayRequest, GatewayResponse};
 use futures::{SinkExt, StreamExt};
 use log::*;
 use serde_json::{from_str, to_string};
 use std::time::Duration;
 use tokio::select;
 
+/// Delay before reconnecting to the manager after a failure. It is doubled on
+/// every consecutive failure, up to [`RECONNECT_DELAY_MAX`].
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+
+/// Maximum delay before reconnecting to the manager.
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
 pub async fn connect(global: Global) {
     info!("Connecting to manager at {}", global.manager);
+    let mut delay = RECONNECT_DELAY_MIN;
     loop {
         // try connecting to websocket
-        match connect_run(&global).await {
+        let mut connected = false;
+        match connect_run(&global, &mut connected).await {
             Ok(()) => break,
             Err(e) => error!("Error connecting to websocket: {}", e),
         };
 
+        // start backing off from scratch if the connection was established
+        // before it dropped
+        if connected {
+            delay = RECONNECT_DELAY_MIN;
+        }
+
         // wait some time to reconnect
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        info!(
+            "Reconnecting to manager in {}",
+            humantime::format_duration(delay)
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_DELAY_MAX);
     }
 }
 
-pub async fn connect_run(global: &Global) -> Result<()> {
+/// Connect to the manager and handle its requests until the connection
+/// drops. Sets `connected` once the connection is established.
+pub async fn connect_run(global: &Global, connected: &mut bool) -> Result<()> {
     let request = Request::get(&global.manager.to_string())
         .header("Authorization", &format!("Bearer {}", global.token))
         .header("Identity", &global.options.identity)
@@ -32,33 +54,132 @@ pub async fn connect_run(global: &Global) -> Result<()> {
 
     let (mut socket, _response) = connect_async_with_tls_connector(request, None).await?;
     info!("Connected to websocket at {}", global.manager);
+    *connected = true;
 
     let mut traffic_sub = global.traffic_broadcast.subscribe();
+
+    // send traffic which was not acknowledged before reconnecting
+    let buffered = global.options.traffic_buffer > 0;
+    let mut sent = None;
+    if buffered {
+        send_buffered_traffic(global, &mut socket, &mut sent).await?;
+    }
     let mut events_sub = global.events_broadcast.subscribe();
+    let mut status_sub = global.status_broadcast.subscribe();
 
     loop {
         select! {
             message = socket.next() => {
                 match message {
                     Some(Ok(Message::Text(text))) => {
-                        let message: GatewayRequest = from_str(&text)?;
+                        let message: GatewayRequest = match from_str(&text) {
+                            Ok(message) => message,
+                            Err(error) => {
+                                // a config with an invalid network is rejected
+                                // like a failed apply, keeping the connection
+                                let invalid = from_str::<serde_json::Value>(&text)
+                                    .ok()
+                                    .and_then(|value| {
+                                        let config = value.get("Apply").or_else(|| value.get("ApplyPartial"))?;
+                                        crate::gateway::config_parse_error(config)
+                                    });
+                                match invalid {
+                                    Some(invalid) => {
+                                        error!("Received invalid config: {invalid:#}");
+                                        let result = Err(crate::gateway::invalid(invalid).to_string());
+                                        socket.send(Message::Text(serde_json::to_string(&GatewayResponse::Apply(result))?)).await?;
+                                        continue;
+                                    }
+                                    None => return Err(error.into()),
+                                }
+                            }
+                        };
                         match message {
                             GatewayRequest::Apply(config) => {
-                                let result = match crate::gateway::apply(global, &config).await {
-                                    Ok(()) => Ok(()),
-                                    Err(e) => Err(e.to_string()),
+                                let (result, assigned) = match crate::gateway::apply(global, &config).await {
+                                    Ok(assigned) => (Ok(()), assigned),
+                                    Err(e) => (Err(e.to_string()), Default::default()),
                                 };
+                                let operation = AuditOperation::Apply {
+                                    networks: config.keys().cloned().collect(),
+                                };
+                                audit(global, operation, &result).await;
                                 socket.send(Message::Text(serde_json::to_string(&GatewayResponse::Apply(result))?)).await?;
+                                if !assigned.is_empty() {
+                                    socket.send(Message::Text(serde_json::to_string(&GatewayResponse::Assigned(assigned))?)).await?;
+                                }
                             },
                             GatewayRequest::ApplyPartial(config) => {
-                                let result = match crate::gateway::apply_partial(global, &config).await {
-                                    Ok(()) => Ok(()),
-                                    Err(e) => Err(e.to_string()),
+                                let (result, assigned) = match crate::gateway::apply_partial(global, &config).await {
+                                    Ok(assigned) => (Ok(()), assigned),
+                                    Err(e) => (Err(e.to_string()), Default::default()),
+                                };
+                                let operation = AuditOperation::ApplyPartial {
+                                    changed: config.iter().filter(|(_, network)| network.is_some()).map(|(port, _)| *port).collect(),
+                                    removed: config.iter().filter(|(_, network)| network.is_none()).map(|(port, _)| *port).collect(),
                                 };
+                                audit(global, operation, &result).await;
                                 socket.send(Message::Text(serde_json::to_string(&GatewayResponse::Apply(result))?)).await?;
+                                if !assigned.is_empty() {
+                                    socket.send(Message::Text(serde_json::to_string(&GatewayResponse::Assigned(assigned))?)).await?;
+                                }
+                            },
+                            GatewayRequest::Connected => {
+                                let connected = global.connected();
+                                socket.send(Message::Text(serde_json::to_string(&GatewayResponse::Connected(connected))?)).await?;
+                            },
+                            GatewayRequest::PauseWatchdog => {
+                                info!("Pausing watchdog");
+                                global.pause_watchdog();
+                                socket.send(Message::Text(serde_json::to_string(&GatewayResponse::WatchdogPaused(true))?)).await?;
+                            },
+                            GatewayRequest::ResumeWatchdog => {
+                                info!("Resuming watchdog");
+                                global.resume_watchdog();
+                                socket.send(Message::Text(serde_json::to_string(&GatewayResponse::WatchdogPaused(false))?)).await?;
+                            },
+                            GatewayRequest::Info => {
+                                let info = global.info();
+                                socket.send(Message::Text(serde_json::to_string(&GatewayResponse::Info(info))?)).await?;
+                            },
+                            GatewayRequest::HandshakeRates => {
+                                let rates = global.handshake_rates();
+                                socket.send(Message::Text(serde_json::to_string(&GatewayResponse::HandshakeRates(rates))?)).await?;
+                            },
+                            GatewayRequest::Config => {
+                                let config = global.lock().lock().await.clone();
+                                socket.send(Message::Text(serde_json::to_string(&GatewayResponse::Config(config))?)).await?;
+                            },
+                            GatewayRequest::Status => {
+                                let status = match crate::gateway::status(global).await {
+                                    Ok(status) => status,
+                                    Err(e) => {
+                                        error!("Error reading status: {e:#}");
+                                        Default::default()
+                                    }
+                                };
+                                socket.send(Message::Text(serde_json::to_string(&GatewayResponse::Status(status))?)).await?;
+                            },
+                            GatewayRequest::InterfaceStats => {
+                                let stats = global.interface_stats();
+                                socket.send(Message::Text(serde_json::to_string(&GatewayResponse::InterfaceStats(stats))?)).await?;
+                            },
+                            GatewayRequest::AuditLog => {
+                                let entries = match crate::audit::read(global.options()).await {
+                                    Ok(entries) => entries,
+                                    Err(e) => {
+                                        error!("Error reading audit log: {e:#}");
+                                        vec![]
+                                    }
+                                };
+                                socket.send(Message::Text(serde_json::to_string(&GatewayResponse::AuditLog(entries))?)).await?;
+                            },
+                            GatewayRequest::TrafficAck(cursor) => {
+                                global.ack_traffic(cursor);
                             },
                             GatewayRequest::Shutdown => {
                                 error!("Received Shutdown message, shutting down");
+                                audit(global, AuditOperation::Shutdown, &Ok(())).await;
                                 break;
                             }
                         }
@@ -70,9 +191,13 @@ pub async fn connect_run(global: &Global) -> Result<()> {
             },
             traffic = traffic_sub.recv() => {
                 let traffic = traffic?;
-                let message = GatewayResponse::Traffic(traffic);
-                let message = to_string(&message)?;
-                socket.send(Message::Text(message)).await?;
+                if buffered {
+                    send_buffered_traffic(global, &mut socket, &mut sent).await?;
+                } else {
+                    let message = GatewayResponse::Traffic(traffic);
+                    let message = to_string(&message)?;
+                    socket.send(Message::Text(message)).await?;
+                }
             }
             event = events_sub.recv() => {
                 let event = event?;
@@ -80,8 +205,42 @@ pub async fn connect_run(global: &Global) -> Result<()> {
                 let message = to_string(&message)?;
                 socket.send(Message::Text(message)).await?;
             }
+            status = status_sub.recv() => {
+                let status = status?;
+                let message = GatewayResponse::Status(status);
+                let message = to_string(&message)?;
+                socket.send(Message::Text(message)).await?;
+            }
         }
     }
 
     Ok(())
 }
+
+/// Send the buffered traffic which was not sent over this connection yet,
+/// remembering the cursor of the last one sent.
+async fn send_buffered_traffic<S>(
+    global: &Global,
+    socket: &mut S,
+    sent: &mut Option<u64>,
+) -> Result<()>
+where
+    S: futures::Sink<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    for buffered in global.buffered_traffic(*sent) {
+        *sent = Some(buffered.cursor);
+        let message = to_string(&GatewayResponse::BufferedTraffic(buffered))?;
+        socket.send(Message::Text(message)).await?;
+    }
+    Ok(())
+}
+
+/// Record a change requested by the manager in the audit log. Failing to
+/// write the audit log is logged, but does not affect the change.
+async fn audit(global: &Global, operation: AuditOperation, result: &Result<(), String>) {
+    let error = result.as_ref().err().cloned();
+    if let Err(e) = crate::audit::record(global, operation, error).await {
+        error!("Error writing audit log: {e:#}");
+    }
+}